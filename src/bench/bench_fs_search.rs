@@ -42,12 +42,18 @@ fn main() {
         roots.into_iter().map(Ok),
         roots_count,
         &ignores,
+        &find::Opts::default(),
         || Ok(()),
-        |_, _orig_root: (), path, chan| chan.send(path).map_err(anyhow::Error::new),
+        |_, _orig_root: (), path, _kind, chan| chan.send(path).map_err(anyhow::Error::new),
+        |_orig_root, err, _chan| {
+            eprintln!("{}", err);
+            Ok(())
+        },
         |x| {
             println!("{:?}", x);
             Ok(())
-        }
+        },
+        || false,
     );
 
     res.unwrap();
@@ -0,0 +1,135 @@
+// Copyright 2021 Sergey Vinokurov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Parts of the native completion style that don’t need Emacs.
+
+use crate::fuzzy_match;
+
+/// Completion string split around the field being completed.
+pub struct Input {
+    /// Part before the field boundary, i.e. what candidates are relative to.
+    pub prefix: String,
+    /// The field that candidates are matched against.
+    pub pattern: String,
+    /// Part after the field boundary.
+    pub suffix: String,
+    /// Position of point in characters.
+    pub point: usize,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum TryCompletion {
+    NoMatch,
+    /// Pattern is the only and exact match.
+    Exact,
+    Completed { string: String, point: usize },
+}
+
+pub fn common_prefix<'a>(xs: &'a str, ys: &str) -> &'a str {
+    let n = xs
+        .char_indices()
+        .zip(ys.chars())
+        .find(|((_, x), y)| x != y)
+        .map_or_else(|| xs.len().min(ys.len()), |((i, _), _)| i);
+    &xs[..n]
+}
+
+/// Extend the field with the common prefix of all `candidates` provided it still matches
+/// them all, leaving the rest of input intact and point at the end of the field. Input is
+/// returned unchanged if there’s no such extension.
+pub fn try_completion(input: &Input, candidates: &[&str]) -> TryCompletion {
+    let mut cands = candidates.iter().copied();
+    let first = match cands.next() {
+        None => return TryCompletion::NoMatch,
+        Some(x) => x,
+    };
+
+    if candidates.len() == 1 && first == input.pattern {
+        return TryCompletion::Exact;
+    }
+
+    let common = cands.fold(first, common_prefix);
+
+    if common.len() > input.pattern.len() && fuzzy_match::is_match(&input.pattern, common) {
+        let mut string = input.prefix.clone() + common;
+        let point = string.chars().count();
+        string.push_str(&input.suffix);
+        TryCompletion::Completed { string, point }
+    } else {
+        TryCompletion::Completed {
+            string: format!("{}{}{}", input.prefix, input.pattern, input.suffix),
+            point: input.point,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn input(prefix: &str, pattern: &str, suffix: &str, point: usize) -> Input {
+        Input { prefix: prefix.to_string(), pattern: pattern.to_string(), suffix: suffix.to_string(), point }
+    }
+
+    fn completed(string: &str, point: usize) -> TryCompletion {
+        TryCompletion::Completed { string: string.to_string(), point }
+    }
+
+    #[test]
+    fn common_prefix_of_unicode() {
+        assert_eq!(common_prefix("ёжик", "ёлка"), "ё");
+        assert_eq!(common_prefix("foo", "foobar"), "foo");
+        assert_eq!(common_prefix("", "foo"), "");
+    }
+
+    #[test]
+    fn try_completion_no_match() {
+        assert_eq!(try_completion(&input("", "fo", "", 2), &[]), TryCompletion::NoMatch);
+    }
+
+    #[test]
+    fn try_completion_exact() {
+        assert_eq!(try_completion(&input("~/", "foo", "", 5), &["foo"]), TryCompletion::Exact);
+    }
+
+    #[test]
+    fn try_completion_extends_field() {
+        assert_eq!(
+            try_completion(&input("~/src/", "fo", "", 8), &["foobar", "foobaz"]),
+            completed("~/src/fooba", 11)
+        );
+    }
+
+    #[test]
+    fn try_completion_keeps_suffix() {
+        assert_eq!(
+            try_completion(&input("~/src/", "fo", "/bar", 8), &["foo", "fooo"]),
+            completed("~/src/foo/bar", 9)
+        );
+    }
+
+    #[test]
+    fn try_completion_unchanged() {
+        // Common prefix doesn’t extend the pattern.
+        assert_eq!(
+            try_completion(&input("~/src/", "fo", "/bar", 7), &["foo", "xfo"]),
+            completed("~/src/fo/bar", 7)
+        );
+        // Common prefix is shorter than the pattern.
+        assert_eq!(
+            try_completion(&input("", "ab", "", 1), &["axb", "ayb"]),
+            completed("ab", 1)
+        );
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright 2021 Sergey Vinokurov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Results of visiting individual directories saved between traversals. A directory is
+//! read again only if its modification time or ignore rules that apply to it have changed.
+
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::find::Kind;
+
+const MAGIC: &[u8] = b"emacs-native-dir-cache 1\n";
+
+/// Directories modified this recently are not cached: a change within the same tick of
+/// filesystem clock would go unnoticed otherwise.
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// What a cached directory entry should be validated against.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Stamp {
+    /// Modification time of the directory itself.
+    pub mtime: SystemTime,
+    /// Fingerprint of ignore rules in effect for the directory.
+    pub rules: u64,
+}
+
+impl Stamp {
+    /// Whether directory was last modified long enough before `read_at` to be cached.
+    pub fn is_settled(&self, read_at: SystemTime) -> bool {
+        read_at.duration_since(self.mtime).is_ok_and(|d| d > RACY_WINDOW)
+    }
+}
+
+/// Directory entry that survived filtering.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Entry {
+    pub name: OsString,
+    pub kind: Kind,
+    pub report: bool,
+    pub descend: bool,
+}
+
+#[derive(Debug)]
+struct CachedDir {
+    stamp: Stamp,
+    entries: Vec<Entry>,
+}
+
+/// Cache loaded from a previous traversal plus results of the current one, which replace
+/// it when saved. Directories that weren’t visited this time are dropped.
+pub struct DirCache {
+    key: String,
+    old: Mutex<HashMap<PathBuf, CachedDir>>,
+    new: Mutex<HashMap<PathBuf, CachedDir>>,
+}
+
+impl fmt::Debug for DirCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DirCache").field("key", &self.key).finish_non_exhaustive()
+    }
+}
+
+impl DirCache {
+    pub fn new(key: String) -> Self {
+        DirCache { key, old: Mutex::new(HashMap::new()), new: Mutex::new(HashMap::new()) }
+    }
+
+    /// Load cache from `file`. Missing or malformed files, as well as ones saved for
+    /// a different `key`, yield an empty cache.
+    pub fn load(file: &Path, key: String) -> io::Result<Self> {
+        let mut res = DirCache::new(key);
+        let bytes = match std::fs::read(file) {
+            Ok(x) => x,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(res),
+            Err(err) => return Err(err),
+        };
+        if let Some(dirs) = decode(&bytes, &res.key) {
+            res.old = Mutex::new(dirs);
+        }
+        Ok(res)
+    }
+
+    /// Entries of `dir` if they were cached with the same stamp.
+    pub fn lookup(&self, dir: &Path, stamp: &Stamp) -> Option<Vec<Entry>> {
+        let mut old = self.old.lock().unwrap();
+        match old.remove(dir) {
+            Some(x) if x.stamp == *stamp => Some(x.entries),
+            _ => None,
+        }
+    }
+
+    pub fn store(&self, dir: PathBuf, stamp: Stamp, entries: Vec<Entry>) {
+        self.new.lock().unwrap().insert(dir, CachedDir { stamp, entries });
+    }
+
+    /// Write directories stored during current traversal into `file`.
+    pub fn save(&self, file: &Path) -> io::Result<()> {
+        let new = self.new.lock().unwrap();
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        put_bytes(&mut out, self.key.as_bytes());
+        put_u64(&mut out, new.len() as u64);
+        for (dir, cached) in new.iter() {
+            put_bytes(&mut out, &os_bytes(dir.as_os_str())?);
+            let mtime = cached.stamp.mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+            put_u64(&mut out, mtime.as_secs());
+            put_u64(&mut out, u64::from(mtime.subsec_nanos()));
+            put_u64(&mut out, cached.stamp.rules);
+            put_u64(&mut out, cached.entries.len() as u64);
+            for e in &cached.entries {
+                let flags = u8::from(e.kind == Kind::Dir) | u8::from(e.report) << 1 | u8::from(e.descend) << 2;
+                out.push(flags);
+                put_bytes(&mut out, &os_bytes(&e.name)?);
+            }
+        }
+
+        // Write a sibling first so that concurrent readers never see a partial file.
+        let mut tmp = file.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        std::fs::File::create(&tmp)?.write_all(&out)?;
+        std::fs::rename(&tmp, file)
+    }
+}
+
+fn put_u64(out: &mut Vec<u8>, x: u64) {
+    out.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, x: &[u8]) {
+    put_u64(out, x.len() as u64);
+    out.extend_from_slice(x);
+}
+
+#[cfg(target_family = "unix")]
+fn os_bytes(s: &std::ffi::OsStr) -> io::Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(s.as_bytes().to_vec())
+}
+
+#[cfg(target_family = "windows")]
+fn os_bytes(s: &std::ffi::OsStr) -> io::Result<Vec<u8>> {
+    s.to_str()
+        .map(|x| x.as_bytes().to_vec())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Non-unicode path: {:?}", s)))
+}
+
+#[cfg(target_family = "unix")]
+fn os_string(bytes: &[u8]) -> Option<OsString> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(std::ffi::OsStr::from_bytes(bytes).to_owned())
+}
+
+#[cfg(target_family = "windows")]
+fn os_string(bytes: &[u8]) -> Option<OsString> {
+    std::str::from_utf8(bytes).ok().map(OsString::from)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if n > self.bytes.len() {
+            return None;
+        }
+        let (x, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Some(x)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|x| x[0])
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8).map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let n = usize::try_from(self.u64()?).ok()?;
+        self.take(n)
+    }
+
+    fn os_string(&mut self) -> Option<OsString> {
+        os_string(self.bytes()?)
+    }
+}
+
+fn decode(bytes: &[u8], key: &str) -> Option<HashMap<PathBuf, CachedDir>> {
+    let mut r = Reader { bytes: bytes.strip_prefix(MAGIC)? };
+    if r.bytes()? != key.as_bytes() {
+        return None;
+    }
+    let n = r.u64()?;
+    let mut res = HashMap::new();
+    for _ in 0..n {
+        let dir = PathBuf::from(r.os_string()?);
+        let secs = r.u64()?;
+        let nanos = u32::try_from(r.u64()?).ok()?;
+        let mtime = UNIX_EPOCH.checked_add(Duration::new(secs, nanos))?;
+        let rules = r.u64()?;
+        let count = r.u64()?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let flags = r.u8()?;
+            entries.push(Entry {
+                kind: if flags & 1 != 0 { Kind::Dir } else { Kind::File },
+                report: flags & 2 != 0,
+                descend: flags & 4 != 0,
+                name: r.os_string()?,
+            });
+        }
+        res.insert(dir, CachedDir { stamp: Stamp { mtime, rules }, entries });
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry { name: OsString::from("foo.rs"), kind: Kind::File, report: true, descend: false },
+            Entry { name: OsString::from("src"), kind: Kind::Dir, report: false, descend: true },
+        ]
+    }
+
+    fn stamp() -> Stamp {
+        Stamp { mtime: UNIX_EPOCH + Duration::new(1_600_000_000, 123), rules: 42 }
+    }
+
+    /// File in temporary directory that is removed once the test finishes.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            TempFile(std::env::temp_dir().join(format!("dir-cache-test-{}-{}", std::process::id(), name)))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn saved(name: &str) -> TempFile {
+        let file = TempFile::new(name);
+        let cache = DirCache::new("key".to_string());
+        cache.store(PathBuf::from("/root"), stamp(), entries());
+        cache.save(&file.0).unwrap();
+        file
+    }
+
+    #[test]
+    fn round_trip() {
+        let file = saved("round-trip");
+        let cache = DirCache::load(&file.0, "key".to_string()).unwrap();
+        assert_eq!(cache.lookup(Path::new("/root"), &stamp()), Some(entries()));
+        // Entries are handed out once.
+        assert_eq!(cache.lookup(Path::new("/root"), &stamp()), None);
+    }
+
+    #[test]
+    fn stale_stamp() {
+        let file = saved("stale-stamp");
+        let cache = DirCache::load(&file.0, "key".to_string()).unwrap();
+        assert_eq!(cache.lookup(Path::new("/root"), &Stamp { rules: 43, ..stamp() }), None);
+    }
+
+    #[test]
+    fn mismatched_key() {
+        let file = saved("mismatched-key");
+        let cache = DirCache::load(&file.0, "other key".to_string()).unwrap();
+        assert_eq!(cache.lookup(Path::new("/root"), &stamp()), None);
+    }
+
+    #[test]
+    fn truncated() {
+        let file = saved("truncated");
+        let bytes = std::fs::read(&file.0).unwrap();
+        for len in [0, MAGIC.len(), bytes.len() / 2, bytes.len() - 1] {
+            assert!(decode(&bytes[..len], "key").is_none(), "length {}", len);
+        }
+        assert!(decode(&bytes, "key").is_some());
+    }
+
+    #[test]
+    fn missing_file() {
+        let file = TempFile::new("missing");
+        let cache = DirCache::load(&file.0, "key".to_string()).unwrap();
+        assert_eq!(cache.lookup(Path::new("/root"), &stamp()), None);
+    }
+}
@@ -18,7 +18,11 @@ use std::result;
 use emacs;
 use emacs::{Env, Result, Value, Vector, FromLisp, IntoLisp};
 
-emacs::use_symbols!(nil setcdr);
+use crate::emacs_env_ext;
+use crate::fuzzy_match::{field_weight, FieldWeight};
+
+emacs::use_symbols!(nil setcdr string cons vector symbol integer hash_table obarray
+                    maphash mapatoms symbol_name plist_get plist_member);
 
 pub fn path_to_string(path: PathBuf) -> result::Result<String, String> {
     match path.to_str() {
@@ -27,6 +31,69 @@ pub fn path_to_string(path: PathBuf) -> result::Result<String, String> {
     }
 }
 
+pub fn is_string(x: Value) -> Result<bool> {
+    Ok(x.env.type_of(x)?.eq(string.bind(x.env)))
+}
+
+pub fn is_cons(x: Value) -> Result<bool> {
+    Ok(x.env.type_of(x)?.eq(cons.bind(x.env)))
+}
+
+/// Decode a string or a symbol the way `all-completions` treats completion candidates.
+fn decode_name(x: Value) -> Result<String> {
+    let env = x.env;
+    if env.type_of(x)?.eq(symbol.bind(env)) {
+        env.call(symbol_name, (x,))?.into_rust()
+    } else {
+        x.into_rust()
+    }
+}
+
+/// Decode completion candidate into its fields. Candidate is either a plain string, a symbol,
+/// a cons of strings `(a . b)`, a list of strings `(a b c)` or a vector of strings `[a b c]`.
+/// Alist entries like `("a" . data)` or `(a . data)` with anything but a string or a list
+/// in cdr have a single field, like in completion tables. Only fields with non-zero weight
+/// in `weights` are decoded, the rest are left empty.
+pub fn decode_fields(x: Value, weights: &[FieldWeight]) -> Result<Vec<String>> {
+    let env = x.env;
+    let wanted = |i: usize| field_weight(weights, i) != 0;
+    let decode = |i: usize, y: Value| -> Result<String> {
+        if wanted(i) { decode_name(y) } else { Ok(String::new()) }
+    };
+    let typ = env.type_of(x)?;
+    if typ.eq(string.bind(env)) || typ.eq(symbol.bind(env)) {
+        Ok(vec![decode(0, x)?])
+    } else if typ.eq(cons.bind(env)) {
+        let first = decode(0, x.car()?)?;
+        let rest: Value = x.cdr()?;
+        if is_string(rest)? {
+            Ok(vec![first, decode(1, rest)?])
+        } else if is_cons(rest)? {
+            let mut res = vec![first];
+            for (i, y) in ListIter::new(rest).enumerate() {
+                res.push(decode(i + 1, y?)?);
+            }
+            Ok(res)
+        } else {
+            Ok(vec![first])
+        }
+    } else if typ.eq(vector.bind(env)) {
+        x.into_rust::<Vector>()?.into_iter().enumerate().map(|(i, y)| decode(i, y)).collect()
+    } else {
+        Err(anyhow::Error::msg("Invalid candidate, expected string, symbol, cons or vector of strings"))
+    }
+}
+
+/// Value of `key` in property list `plist`, nil if it’s absent.
+pub fn plist_lookup<'a>(plist: Value<'a>, key: Value<'a>) -> Result<Value<'a>> {
+    plist.env.call(plist_get, (plist, key))
+}
+
+/// Whether property list `plist` has `key`, even if its value is nil.
+pub fn plist_contains<'a>(plist: Value<'a>, key: Value<'a>) -> Result<bool> {
+    Ok(plist.env.call(plist_member, (plist, key))?.is_not_nil())
+}
+
 pub fn to_strings_iter<'a>(
     input: Value<'a>
 ) -> DecodingListIter<'a, String>
@@ -223,6 +290,16 @@ impl<'a> IncrementalResList<'a> {
     pub fn finalize(self) -> Result<Value<'a>> {
         self.store.cdr()
     }
+
+    /// Finish the list with an improper tail, e.g. base size for completion results.
+    pub fn finalize_with_tail<A>(self, tail: A) -> Result<Value<'a>>
+        where
+        A: IntoLisp<'a>,
+    {
+        let env = self.env();
+        env.call(setcdr, (self.last_cell, tail.into_lisp(env)?))?;
+        self.store.cdr()
+    }
 }
 
 pub fn to_list<'a, I, A>(env: &'a Env, iter: I) -> emacs::Result<Value<'a>>
@@ -268,6 +345,190 @@ impl<'a> Iterator for ListIter<'a> {
     }
 }
 
+pub struct VectorIter<'a> {
+    vec: Vector<'a>,
+    idx: usize,
+}
+
+impl<'a> VectorIter<'a> {
+    pub fn new(vec: Vector<'a>) -> Self {
+        VectorIter { vec, idx: 0 }
+    }
+}
+
+impl<'a> Iterator for VectorIter<'a> {
+    type Item = emacs::Result<Value<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx < self.vec.len() {
+            let x = self.vec.get(self.idx);
+            self.idx += 1;
+            Some(x)
+        } else {
+            None
+        }
+    }
+}
+
+enum CollectionElems<'a> {
+    List(ListIter<'a>),
+    Vector(VectorIter<'a>),
+    /// Names of hash table keys or obarray symbols that satisfy the predicate.
+    Names(std::vec::IntoIter<String>),
+}
+
+/// Contents of a vector slot as far as telling obarrays from plain vectors goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Slot {
+    Symbol,
+    Zero,
+    Other,
+}
+
+/// Before Emacs 30 obarrays are vectors whose occupied buckets hold symbols and empty ones 0.
+fn is_obarray_slots(slots: impl Iterator<Item = Result<Slot>>) -> Result<bool> {
+    let mut empty = true;
+    for x in slots {
+        if x? == Slot::Other {
+            return Ok(false);
+        }
+        empty = false;
+    }
+    Ok(!empty)
+}
+
+fn slot(x: Value) -> Result<Slot> {
+    let env = x.env;
+    let typ = env.type_of(x)?;
+    if typ.eq(symbol.bind(env)) {
+        Ok(Slot::Symbol)
+    } else if typ.eq(integer.bind(env)) && x.into_rust::<i64>()? == 0 {
+        Ok(Slot::Zero)
+    } else {
+        Ok(Slot::Other)
+    }
+}
+
+/// Name of a hash table entry or obarray symbol if completion would consider it. Only string
+/// and symbol keys count, those that `keep` rejects are skipped. `keep` is not called for
+/// other keys.
+fn table_entry_name(name: Option<String>, keep: impl FnOnce() -> Result<bool>) -> Result<Option<String>> {
+    match name {
+        Some(x) if keep()? => Ok(Some(x)),
+        _ => Ok(None),
+    }
+}
+
+/// Decode `x` if it is a string or a symbol.
+fn decode_table_key(x: Value) -> Result<Option<String>> {
+    let env = x.env;
+    let typ = env.type_of(x)?;
+    if typ.eq(string.bind(env)) {
+        Ok(Some(x.into_rust()?))
+    } else if typ.eq(symbol.bind(env)) {
+        Ok(Some(env.call(symbol_name, (x,))?.into_rust()?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Names of entries of hash table or obarray `coll` that satisfy `pred`, called like during
+/// completion: with key and value for hash tables and with symbol for obarrays.
+fn table_names(coll: Value, pred: Option<Value>, is_hash_table: bool) -> Result<Vec<String>> {
+    let env = coll.env;
+    let mut names = Vec::new();
+    let mut visit = |args: &emacs::CallEnv| -> Result<()> {
+        let key = args.get_arg(0);
+        let keep = || -> Result<bool> {
+            match pred {
+                None => Ok(true),
+                Some(p) => {
+                    let res = if is_hash_table { args.call(p, (key, args.get_arg(1)))? } else { args.call(p, (key,))? };
+                    Ok(res.is_not_nil())
+                }
+            }
+        };
+        if let Some(x) = table_entry_name(decode_table_key(key)?, keep)? {
+            names.push(x);
+        }
+        Ok(())
+    };
+    if is_hash_table {
+        emacs_env_ext::with_callback(env, 2, &mut visit, |f| env.call(maphash, (f, coll)).map(|_| ()))?;
+    } else {
+        emacs_env_ext::with_callback(env, 1, &mut visit, |f| env.call(mapatoms, (f, coll)).map(|_| ()))?;
+    }
+    Ok(names)
+}
+
+/// Iterator over elements of any collection that completion functions accept: lists,
+/// vectors, hash tables and obarrays. Hash tables yield names of their string and symbol
+/// keys and obarrays yield names of their symbols.
+pub struct CollectionIter<'a> {
+    env: &'a Env,
+    elems: CollectionElems<'a>,
+    pred: Option<Value<'a>>,
+}
+
+impl<'a> CollectionIter<'a> {
+    /// Only elements that satisfy `pred` will be produced, unless it is nil. Predicate gets
+    /// called the same way as during completion.
+    pub fn new(coll: Value<'a>, pred: Value<'a>) -> Result<Self> {
+        let env = coll.env;
+        let pred = if pred.is_not_nil() { Some(pred) } else { None };
+
+        if !coll.is_not_nil() || is_cons(coll)? {
+            return Ok(CollectionIter { env, elems: CollectionElems::List(ListIter::new(coll)), pred });
+        }
+
+        let typ = env.type_of(coll)?;
+        let is_hash_table = typ.eq(hash_table.bind(env));
+        if typ.eq(vector.bind(env)) {
+            let v: Vector = coll.into_rust()?;
+            if !is_obarray_slots(VectorIter::new(v).map(|x| slot(x?)))? {
+                return Ok(CollectionIter { env, elems: CollectionElems::Vector(VectorIter::new(v)), pred });
+            }
+        } else if !is_hash_table && !typ.eq(obarray.bind(env)) {
+            return Err(anyhow::Error::msg("Invalid collection, expected list, vector, hash table or obarray"));
+        }
+
+        let names = table_names(coll, pred, is_hash_table)?;
+        Ok(CollectionIter { env, elems: CollectionElems::Names(names.into_iter()), pred: None })
+    }
+
+    fn next_elem(&mut self) -> Option<emacs::Result<Value<'a>>> {
+        match &mut self.elems {
+            CollectionElems::List(iter) => iter.next(),
+            CollectionElems::Vector(iter) => iter.next(),
+            CollectionElems::Names(iter) => iter.next().map(|x| x.into_lisp(self.env)),
+        }
+    }
+}
+
+impl<'a> Iterator for CollectionIter<'a> {
+    type Item = emacs::Result<Value<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let x = match self.next_elem()? {
+                Err(err) => return Some(Err(err)),
+                Ok(x) => x,
+            };
+            match self.pred {
+                None => return Some(Ok(x)),
+                Some(pred) => match x.env.call(pred, (x,)) {
+                    Err(err) => return Some(Err(err)),
+                    Ok(res) => {
+                        if res.is_not_nil() {
+                            return Some(Ok(x));
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
+
 pub struct DecodingListIter<'a, A> {
     iter: ListIter<'a>,
     item: std::marker::PhantomData<A>,
@@ -293,3 +554,41 @@ impl<'a, A: FromLisp<'a>> Iterator for DecodingListIter<'a, A> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn is_obarray(slots: &[Slot]) -> bool {
+        is_obarray_slots(slots.iter().map(|x| Ok(*x))).unwrap()
+    }
+
+    #[test]
+    fn string_vector_is_not_obarray() {
+        assert!(!is_obarray(&[Slot::Other, Slot::Other]));
+        assert!(!is_obarray(&[Slot::Symbol, Slot::Other, Slot::Zero]));
+        assert!(!is_obarray(&[]));
+    }
+
+    #[test]
+    fn obarray_vector() {
+        assert!(is_obarray(&[Slot::Zero, Slot::Symbol, Slot::Zero]));
+        assert!(is_obarray(&[Slot::Zero, Slot::Zero]));
+    }
+
+    #[test]
+    fn hash_table_without_predicate() {
+        let name = table_entry_name(Some("foo".to_string()), || Ok(true)).unwrap();
+        assert_eq!(name.as_deref(), Some("foo"));
+    }
+
+    #[test]
+    fn hash_table_with_predicate() {
+        assert_eq!(table_entry_name(Some("foo".to_string()), || Ok(false)).unwrap(), None);
+        let err = table_entry_name(Some("foo".to_string()), || Err(anyhow::Error::msg("failed")));
+        assert!(err.is_err());
+        // Predicate is not called for keys that are neither strings nor symbols.
+        let name = table_entry_name(None, || panic!("predicate called")).unwrap();
+        assert_eq!(name, None);
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright 2021 Sergey Vinokurov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module API functions added after Emacs 25, which the `emacs` crate doesn’t expose.
+//! Each one is checked for availability at runtime via the size of the environment.
+
+use std::mem::{offset_of, size_of};
+use std::os::raw::{c_int, c_void};
+
+use emacs::func::{HandleCall, Manage};
+use emacs::raw::{emacs_env, emacs_value};
+use emacs::{CallEnv, Env, Result, Value};
+
+type Placeholder = Option<unsafe extern "C" fn()>;
+
+/// Layout of `emacs_env_28` from `emacs-module.h`.
+#[repr(C)]
+struct EmacsEnv28 {
+    base: emacs_env,
+    // Emacs 26.
+    should_quit: Option<unsafe extern "C" fn(env: *mut emacs_env) -> bool>,
+    // Emacs 27.
+    process_input: Option<unsafe extern "C" fn(env: *mut emacs_env) -> c_int>,
+    extract_time: Placeholder,
+    make_time: Placeholder,
+    extract_big_integer: Placeholder,
+    make_big_integer: Placeholder,
+    // Emacs 28.
+    get_function_finalizer: Placeholder,
+    set_function_finalizer: Placeholder,
+    open_channel: Option<unsafe extern "C" fn(env: *mut emacs_env, pipe_process: emacs_value) -> c_int>,
+    make_interactive: Placeholder,
+    make_unibyte_string: Placeholder,
+}
+
+/// Environment of the running Emacs if it provides the field ending at `end`.
+fn ext_env(env: &Env, end: usize) -> Option<&EmacsEnv28> {
+    let raw = env.raw();
+    // Safety: `size` is the first field of every environment version and tells how many
+    // of the following fields are valid.
+    unsafe {
+        if (*raw).size as usize >= end {
+            Some(&*(raw as *const EmacsEnv28))
+        } else {
+            None
+        }
+    }
+}
+
+macro_rules! ext_fn {
+    ($env:expr, $field:ident) => {
+        ext_env($env, offset_of!(EmacsEnv28, $field) + size_of::<Placeholder>()).and_then(|e| e.$field)
+    };
+}
+
+/// Call `f` with raw representation of `v`. `Value` doesn’t expose it, but a `GlobalRef`
+/// is a transparent wrapper around a raw value referring to the same object.
+fn with_raw_value<A>(v: Value, f: impl FnOnce(emacs_value) -> A) -> Result<A> {
+    let global = v.make_global_ref();
+    let raw: emacs_value = unsafe { std::mem::transmute_copy(&global) };
+    let res = f(raw);
+    global.free(v.env)?;
+    Ok(res)
+}
+
+type Callback<'f> = &'f mut dyn FnMut(&CallEnv) -> Result<()>;
+
+/// Call `f` with a Lisp function taking `arity` arguments that passes them to `callback`,
+/// e.g. to walk a hash table with `maphash` without building a list of its keys. The function
+/// must not be called after `f` returns. Errors of `callback` are signaled in Lisp so that
+/// they propagate through whatever called the function.
+pub fn with_callback<'e, A>(
+    env: &'e Env,
+    arity: usize,
+    mut callback: Callback,
+    f: impl FnOnce(Value<'e>) -> Result<A>,
+) -> Result<A> {
+    unsafe extern "C" fn trampoline(
+        env: *mut emacs_env,
+        nargs: isize,
+        args: *mut emacs_value,
+        data: *mut c_void,
+    ) -> emacs_value {
+        let env = CallEnv::new(Env::new(env), nargs, args);
+        // Safety: `data` points to the callback that outlives the function, see `with_callback`.
+        env.handle_call(|env| (*(data as *mut Callback))(env))
+    }
+
+    let data = &mut callback as *mut Callback as *mut c_void;
+    // Safety: `trampoline` only uses `data` as a callback which stays alive until `f` returns.
+    let func = unsafe { env.make_function(trampoline, arity..arity, "", data)? };
+    f(func)
+}
+
+/// Discard the pending signal, if any, so that a plain error or a normal result can be
+/// reported instead.
+pub fn clear_signal(env: &Env) {
+    let raw = env.raw();
+    unsafe {
+        if let Some(f) = (*raw).non_local_exit_clear {
+            f(raw);
+        }
+    }
+}
+
+/// Whether user asked to quit, e.g. by pressing C-g. On Emacs 27 and later pending input is
+/// processed so that quit is noticed in graphical sessions too, and a `quit` signal is left
+/// pending when true is returned. Always false on Emacs 25.
+pub fn should_quit(env: &Env) -> bool {
+    const EMACS_PROCESS_INPUT_QUIT: c_int = 1;
+    if let Some(f) = ext_fn!(env, process_input) {
+        unsafe { f(env.raw()) == EMACS_PROCESS_INPUT_QUIT }
+    } else if let Some(f) = ext_fn!(env, should_quit) {
+        unsafe { f(env.raw()) }
+    } else {
+        false
+    }
+}
+
+/// Open a channel to a pipe process created by `make-pipe-process`. Anything written into
+/// the returned file shows up as the process’s output. Requires Emacs 28.
+#[cfg(target_family = "unix")]
+pub fn open_channel(pipe_process: Value) -> Result<std::fs::File> {
+    use std::os::unix::io::FromRawFd;
+
+    let env = pipe_process.env;
+    let f = ext_fn!(env, open_channel)
+        .ok_or_else(|| anyhow::Error::msg("Streaming into a pipe process requires Emacs 28 or later"))?;
+    let fd = with_raw_value(pipe_process, |p| unsafe { f(env.raw(), p) })?;
+    if fd < 0 {
+        clear_signal(env);
+        return Err(anyhow::Error::msg("Failed to open channel to the pipe process"));
+    }
+    // Safety: Emacs hands ownership of the descriptor over to us.
+    Ok(unsafe { std::fs::File::from_raw_fd(fd) })
+}
+
+#[cfg(target_family = "windows")]
+pub fn open_channel(_pipe_process: Value) -> Result<std::fs::File> {
+    Err(anyhow::Error::msg("Streaming into a pipe process is not supported on Windows"))
+}
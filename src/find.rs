@@ -12,21 +12,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::mem::MaybeUninit;
-use std::path::PathBuf;
+use std::ffi::OsStr;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::sync::Arc;
 use std::sync::Barrier;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
 
 use crossbeam::queue::ArrayQueue;
 
 use anyhow::{self, Result};
 use crossbeam;
 use crossbeam::thread::ScopedJoinHandle;
-use globset::{Glob, GlobSet, GlobBuilder, GlobSetBuilder};
+use fnv::FnvHasher;
+use globset::{GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 
-fn mk_glob(pat: &str, case_insens: bool) -> result::Result<Glob, globset::Error> {
+use crate::dir_cache::{self, DirCache};
+
+fn mk_glob(pat: &str, case_insens: bool) -> result::Result<globset::Glob, globset::Error> {
     let mut b = GlobBuilder::new(pat);
     b.case_insensitive(case_insens);
     b.literal_separator(false);
@@ -38,34 +47,394 @@ fn glob_should_test_against_abs(pat: &str) -> bool {
     pat.chars().any(std::path::is_separator)
 }
 
-const THREADS: usize = 2;
+/// Number of workers to use when not specified explicitly, 0 means available parallelism.
+static DEFAULT_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Set number of workers used by traversals that don’t specify it. `None` restores
+/// the default of using available parallelism.
+pub fn set_default_threads(threads: Option<NonZeroUsize>) {
+    DEFAULT_THREADS.store(threads.map_or(0, NonZeroUsize::get), Ordering::Relaxed);
+}
+
+/// Most workers that may be set as the default, several per available core.
+pub fn max_default_threads() -> NonZeroUsize {
+    let cores = std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN);
+    cores.saturating_mul(NonZeroUsize::new(4).unwrap())
+}
+
+pub fn default_threads() -> NonZeroUsize {
+    NonZeroUsize::new(DEFAULT_THREADS.load(Ordering::Relaxed))
+        .or_else(|| std::thread::available_parallelism().ok())
+        .unwrap_or(NonZeroUsize::MIN)
+}
+
+/// Settings of a traversal.
+#[derive(Clone, Debug)]
+pub struct Opts {
+    pub threads: NonZeroUsize,
+    /// Descend into symlinked directories and report symlinked files.
+    pub follow_symlinks: bool,
+    /// Don’t report entries deeper than this, entries directly within a root have depth 1.
+    pub max_depth: Option<usize>,
+    /// Don’t report entries shallower than this.
+    pub min_depth: usize,
+    pub filter: MetaFilter,
+    pub mode: Mode,
+    /// Reuse entries of unchanged directories from a previous traversal and record
+    /// entries of this one. Must not be combined with `filter`.
+    pub cache: Option<Arc<DirCache>>,
+    /// Don’t descend into directories on filesystems other than that of the root they were
+    /// reached from, report them as errors instead. Has no effect on Windows.
+    pub same_filesystem: bool,
+}
+
+impl Default for Opts {
+    fn default() -> Self {
+        Opts {
+            threads: default_threads(),
+            follow_symlinks: false,
+            max_depth: None,
+            min_depth: 0,
+            filter: MetaFilter::default(),
+            mode: Mode::Files,
+            cache: None,
+            same_filesystem: false,
+        }
+    }
+}
+
+/// What kinds of entries to report.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    Files,
+    Dirs,
+    All,
+}
+
+impl Mode {
+    fn reports(self, kind: Kind) -> bool {
+        matches!((self, kind), (Mode::All, _) | (Mode::Files, Kind::File) | (Mode::Dirs, Kind::Dir))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    File,
+    Dir,
+}
+
+/// Conditions on file metadata, all of which must hold for a file to be reported.
+#[derive(Clone, Debug, Default)]
+pub struct MetaFilter {
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Modified at or after this time.
+    pub newer_than: Option<SystemTime>,
+    /// Modified before this time.
+    pub older_than: Option<SystemTime>,
+    pub executable: bool,
+    pub empty: bool,
+}
+
+#[cfg(target_family = "unix")]
+fn is_executable(_path: &Path, meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(target_family = "windows")]
+fn is_executable(path: &Path, _meta: &std::fs::Metadata) -> bool {
+    match path.extension().and_then(|x| x.to_str()) {
+        Some(ext) => ["exe", "com", "bat", "cmd"].iter().any(|x| ext.eq_ignore_ascii_case(x)),
+        None => false,
+    }
+}
+
+impl MetaFilter {
+    /// Whether metadata has to be fetched at all.
+    pub fn is_active(&self) -> bool {
+        self.min_size.is_some()
+            || self.max_size.is_some()
+            || self.newer_than.is_some()
+            || self.older_than.is_some()
+            || self.executable
+            || self.empty
+    }
+
+    pub fn matches(&self, path: &Path, meta: &std::fs::Metadata) -> io::Result<bool> {
+        let size = meta.len();
+        if self.min_size.is_some_and(|x| size < x)
+            || self.max_size.is_some_and(|x| size > x)
+            || (self.empty && size != 0)
+            || (self.executable && !is_executable(path, meta))
+        {
+            return Ok(false);
+        }
+        if self.newer_than.is_some() || self.older_than.is_some() {
+            let mtime = meta.modified()?;
+            if self.newer_than.is_some_and(|x| mtime < x) || self.older_than.is_some_and(|x| mtime >= x) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// How often to check whether traversal should be abandoned.
+const QUIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Error returned when traversal was abandoned because user asked to quit.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Calls `should_quit` at most once per `QUIT_POLL_INTERVAL`.
+pub struct QuitPoller<F> {
+    should_quit: F,
+    last_poll: Instant,
+}
+
+impl<F: FnMut() -> bool> QuitPoller<F> {
+    pub fn new(should_quit: F) -> Self {
+        QuitPoller { should_quit, last_poll: Instant::now() }
+    }
+
+    pub fn poll(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.last_poll) < QUIT_POLL_INTERVAL {
+            return false;
+        }
+        self.last_poll = now;
+        (self.should_quit)()
+    }
+}
+
+/// Problem with a single entry that doesn’t prevent traversal of the rest.
+#[derive(Debug)]
+pub struct EntryError {
+    pub path: PathBuf,
+    pub err: io::Error,
+}
+
+impl std::fmt::Display for EntryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.err)
+    }
+}
+
+/// Globs of a single kind in the order they were given, see `GlobEntry`.
+#[derive(Clone)]
+pub struct Globs {
+    set: GlobSet,
+    /// Position among all globs of the owning `GlobEntry` and whether glob is negated,
+    /// indexed the same way as `set`.
+    order: Vec<(usize, bool)>,
+}
+
+impl Globs {
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+
+    /// Position and negation of the last glob that matches.
+    fn last_match(&self, cand: &globset::Candidate) -> Option<(usize, bool)> {
+        self.set.matches_candidate(cand).into_iter().map(|i| self.order[i]).max()
+    }
+}
 
+/// Glob split into path components, each paired with whether it is `**` that spans any
+/// number of components.
+#[derive(Clone)]
+struct ComponentGlob(Vec<(GlobMatcher, bool)>);
+
+impl ComponentGlob {
+    fn new(pat: &str, case_insens: bool) -> result::Result<Self, globset::Error> {
+        pat.split('/')
+            .map(|c| Ok((mk_glob(c, case_insens)?.compile_matcher(), c == "**")))
+            .collect::<result::Result<_, _>>()
+            .map(ComponentGlob)
+    }
+
+    /// Whether glob may match some path strictly below directory `dir`, given as its components,
+    /// while naming `dir` explicitly: leading `**` doesn’t match the last component of `dir`,
+    /// so that `**/build/generated` reaches below `build` but not below every directory.
+    fn may_match_below(&self, dir: &[&str]) -> bool {
+        Self::go(&self.0, dir, true)
+    }
+
+    fn go(globs: &[(GlobMatcher, bool)], dir: &[&str], leading: bool) -> bool {
+        match (globs.split_first(), dir.split_first()) {
+            (Some(((_, true), rest)), Some((_, cs))) => {
+                // Swallowing the last component leaves `**` to match below `dir`.
+                Self::go(rest, dir, false) || if cs.is_empty() { !leading } else { Self::go(globs, cs, leading) }
+            }
+            (Some(((g, false), rest)), Some((c, cs))) => {
+                g.is_match(c) && if cs.is_empty() { !rest.is_empty() } else { Self::go(rest, cs, false) }
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Globs matched against entry names (`rel`) and against full paths (`abs`). A glob prefixed
+/// with `!` re-includes what previous globs matched, so that the last matching glob decides.
+#[derive(Clone)]
 pub struct GlobEntry {
-    pub rel: GlobSet,
-    pub abs: GlobSet,
+    pub rel: Globs,
+    pub abs: Globs,
+    has_negated: bool,
+    /// Negated globs tested against full paths, that may re-include entries below
+    /// directories the entry matches.
+    reinclude_below: Vec<ComponentGlob>,
 }
 
-pub struct IgnoreAllow {
-    pub ignore: GlobEntry,
-    pub allow: GlobEntry,
-    pub have_rel: bool,
-    pub have_abs: bool,
+struct GlobEntryBuilder {
+    rel: GlobSetBuilder,
+    rel_order: Vec<(usize, bool)>,
+    abs: GlobSetBuilder,
+    abs_order: Vec<(usize, bool)>,
+    has_negated: bool,
+    reinclude_below: Vec<ComponentGlob>,
+}
+
+impl GlobEntryBuilder {
+    fn new() -> Self {
+        GlobEntryBuilder {
+            rel: GlobSetBuilder::new(),
+            rel_order: Vec::new(),
+            abs: GlobSetBuilder::new(),
+            abs_order: Vec::new(),
+            has_negated: false,
+            reinclude_below: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, pat: &str, case_insens: bool, negated: bool, is_abs: bool) -> result::Result<(), globset::Error> {
+        let glob = mk_glob(pat, case_insens)?;
+        let pos = self.rel_order.len() + self.abs_order.len();
+        if is_abs {
+            self.abs.add(glob);
+            self.abs_order.push((pos, negated));
+            if negated {
+                self.reinclude_below.push(ComponentGlob::new(pat, case_insens)?);
+            }
+        } else {
+            self.rel.add(glob);
+            self.rel_order.push((pos, negated));
+        }
+        self.has_negated |= negated;
+        Ok(())
+    }
+
+    fn build(self) -> result::Result<GlobEntry, globset::Error> {
+        Ok(GlobEntry {
+            rel: Globs { set: self.rel.build()?, order: self.rel_order },
+            abs: Globs { set: self.abs.build()?, order: self.abs_order },
+            has_negated: self.has_negated,
+            reinclude_below: self.reinclude_below,
+        })
+    }
+}
+
+/// Split off leading `!` that negates a glob. Use `\!` for globs that start with
+/// exclamation mark.
+fn parse_negation(pat: &str) -> (bool, &str) {
+    if let Some(x) = pat.strip_prefix('!') {
+        (true, x)
+    } else if pat.starts_with("\\!") {
+        (false, &pat[1..])
+    } else {
+        (false, pat)
+    }
 }
 
 impl GlobEntry {
+    /// Position and negation of the last glob that matches either candidate.
+    fn last_match(&self, rel: Option<&globset::Candidate>, abs: Option<&globset::Candidate>) -> Option<(usize, bool)> {
+        std::cmp::max(
+            rel.and_then(|c| self.rel.last_match(c)),
+            abs.and_then(|c| self.abs.last_match(c)),
+        )
+    }
+
+    /// Whether the last glob that matches either candidate is not negated.
+    fn decide(&self, rel: Option<&globset::Candidate>, abs: Option<&globset::Candidate>) -> bool {
+        if !self.has_negated {
+            rel.is_some_and(|c| self.rel.set.is_match_candidate(c)) ||
+                abs.is_some_and(|c| self.abs.set.is_match_candidate(c))
+        } else {
+            matches!(self.last_match(rel, abs), Some((_, false)))
+        }
+    }
+
+    /// Whether the last glob that matches `path` or its `name` is negated.
+    fn is_reincluded(&self, name: &OsStr, path: &Path) -> bool {
+        matches!(
+            self.last_match(Some(&globset::Candidate::new(name)), Some(&globset::Candidate::new(path))),
+            Some((_, true))
+        )
+    }
+
+    /// Whether some negated glob may match an entry below directory `dir`.
+    fn may_reinclude_below(&self, dir: &Path) -> bool {
+        if self.reinclude_below.is_empty() {
+            return false;
+        }
+        let dir = dir.to_string_lossy();
+        let components: Vec<&str> = dir.split(std::path::is_separator).collect();
+        self.reinclude_below.iter().any(|g| g.may_match_below(&components))
+    }
+
+    /// How traversal treats directory `path` named `name` found in a directory that is
+    /// `pruned` or not when globs of this entry ignore directories: `None` if it’s skipped,
+    /// otherwise whether it is pruned itself, i.e. ignored but traversed for entries
+    /// that negated globs re-include.
+    fn dir_pruning(&self, pruned: bool, name: &OsStr, path: &Path) -> Option<bool> {
+        let last = self.last_match(Some(&globset::Candidate::new(name)), Some(&globset::Candidate::new(path)));
+        match last {
+            Some((_, true)) => Some(false),
+            None if !pruned => Some(false),
+            _ if self.may_reinclude_below(path) => Some(true),
+            _ => None,
+        }
+    }
+
     fn is_match(&self, entry: &std::fs::DirEntry, cache_path: &mut Option<PathBuf>) -> bool {
-        if self.rel.is_match(entry.file_name()) {
+        let name = entry.file_name();
+        let rel_cand = globset::Candidate::new(&name);
+        if self.abs.is_empty() {
+            self.decide(Some(&rel_cand), None)
+        } else if !self.has_negated && self.rel.set.is_match_candidate(&rel_cand) {
             true
-        } else if !self.abs.is_empty() {
+        } else {
             let p = entry.path();
-            let res = self.abs.is_match(&p);
+            let res = self.decide(Some(&rel_cand), Some(&globset::Candidate::new(&p)));
             // Store expensive-to-compute path so that it may be reused.
             *cache_path = Some(p);
             res
-        } else {
-            false
         }
     }
+
+    /// Match both relative and absolute globs against `path`, e.g. a traversal root.
+    pub fn is_path_match(&self, path: &Path) -> bool {
+        let cand = globset::Candidate::new(path);
+        self.decide(Some(&cand), Some(&cand))
+    }
+}
+
+#[derive(Clone)]
+pub struct IgnoreAllow {
+    pub ignore: GlobEntry,
+    pub allow: GlobEntry,
+    pub have_rel: bool,
+    pub have_abs: bool,
 }
 
 impl IgnoreAllow {
@@ -76,51 +445,88 @@ impl IgnoreAllow {
     }
 
     fn is_match(&self, entry: &std::fs::DirEntry, cache_path: &mut Option<PathBuf>) -> bool {
-        if self.have_rel {
-            let name = entry.file_name();
-            let rel_cand = globset::Candidate::new(&name);
+        if !self.have_rel && !self.have_abs {
+            return false;
+        }
+        let name = entry.file_name();
+        let rel_cand = globset::Candidate::new(&name);
 
-            if self.ignore.rel.is_match_candidate(&rel_cand) {
-                return false;
-            }
+        if !self.have_abs {
+            return !self.ignore.decide(Some(&rel_cand), None) && self.allow.decide(Some(&rel_cand), None);
+        }
+        if !self.ignore.has_negated && self.ignore.rel.set.is_match_candidate(&rel_cand) {
+            return false;
+        }
 
-            if self.have_abs {
-                let path = entry.path();
-                let abs_cand = globset::Candidate::new(&path);
+        let path = entry.path();
+        let abs_cand = globset::Candidate::new(&path);
+        let res =
+            !self.ignore.decide(Some(&rel_cand), Some(&abs_cand)) &&
+            self.allow.decide(Some(&rel_cand), Some(&abs_cand));
+        // Store expensive-to-compute path so that it may be reused.
+        *cache_path = Some(path);
+        res
+    }
 
-                if self.ignore.abs.is_match_candidate(&abs_cand) {
-                    *cache_path = Some(path);
-                    return false;
-                }
+    /// Same as `is_match` but for a path that doesn’t come from a directory listing.
+    fn is_path_match(&self, path: &Path) -> bool {
+        let name = match path.file_name() {
+            Some(x) => x,
+            None => return false,
+        };
+        let rel_cand = globset::Candidate::new(name);
+        let abs_cand = globset::Candidate::new(path);
+        !self.ignore.decide(Some(&rel_cand), Some(&abs_cand)) && self.allow.decide(Some(&rel_cand), Some(&abs_cand))
+    }
+}
 
-                let res =
-                    self.allow.rel.is_match_candidate(&rel_cand) ||
-                    self.allow.abs.is_match_candidate(&abs_cand);
+/// What to do with hidden entries, i.e. files and directories whose names start with a dot.
+/// Roots are traversed regardless.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Hidden {
+    #[default]
+    Include,
+    Exclude,
+    /// Exclude all hidden entries except those whose names match any of the globs.
+    ExcludeExcept(Vec<String>),
+}
 
-                // Store expensive-to-compute path so that it may be reused.
-                *cache_path = Some(path);
+/// Whether globs of each list passed to `Ignores::new_with_case` ignore case.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GlobCase {
+    pub globs: bool,
+    pub ignored_files: bool,
+    pub ignored_dirs: bool,
+    pub ignored_dir_prefixes: bool,
+    pub ignored_abs_dirs: bool,
+}
 
-                res
-            } else {
-                self.allow.rel.is_match_candidate(&rel_cand)
-            }
-        } else if self.have_abs {
-            let path = entry.path();
-            let path_cand = globset::Candidate::new(&path);
-            let res =
-                !self.ignore.abs.is_match_candidate(&path_cand) &&
-                self.allow.abs.is_match_candidate(&path_cand);
-            *cache_path = Some(path);
-            res
-        } else {
-            false
+impl Default for GlobCase {
+    fn default() -> Self {
+        GlobCase {
+            globs: true,
+            ignored_files: false,
+            ignored_dirs: false,
+            ignored_dir_prefixes: false,
+            ignored_abs_dirs: false,
         }
     }
 }
 
+#[derive(Clone)]
 pub struct Ignores {
     pub files: IgnoreAllow,
     pub ignored_dirs: GlobEntry,
+    pub case: GlobCase,
+    /// Glob lists as passed to `new_with_case`.
+    spec: [Vec<String>; 5],
+    /// Global git excludes, present only if `.gitignore` and friends should be honored.
+    pub git_ignore: Option<Gitignore>,
+    /// Global excludes file that `git_ignore` was read from along with its modification time.
+    git_excludes_stamp: Option<(PathBuf, Option<SystemTime>)>,
+    pub hidden: Hidden,
+    /// Compiled globs of `Hidden::ExcludeExcept`.
+    hidden_allowed: GlobSet,
 }
 
 fn strip_trailing_slash(s: &str) -> &str {
@@ -148,85 +554,378 @@ impl Ignores {
         S4: AsRef<str>,
         S5: AsRef<str>,
     {
-        let mut wanted_file_abs_builder = GlobSetBuilder::new();
-        let mut wanted_file_rel_builder = GlobSetBuilder::new();
-        let mut ignored_file_abs_builder = GlobSetBuilder::new();
-        let mut ignored_file_rel_builder = GlobSetBuilder::new();
-        let mut ignored_dir_abs_builder = GlobSetBuilder::new();
-        let mut ignored_dir_rel_builder = GlobSetBuilder::new();
+        Ignores::new_with_case(
+            globs,
+            ignored_file_globs,
+            ignored_dir_globs,
+            ignored_dir_prefixes_globs,
+            ignored_abs_dirs,
+            GlobCase::default(),
+        )
+    }
+
+    /// Like `new` but with explicit case sensitivity of every list. Within every list globs
+    /// prefixed with `!` re-include entries that earlier globs matched. Negated directory globs
+    /// that contain a path separator may also re-include files and directories within an
+    /// ignored directory, e.g. `build` followed by `!build/generated/*.hs`. Ignored directories
+    /// that such globs name component by component are traversed but only re-included entries
+    /// are reported from them.
+    pub fn new_with_case<E, I1, I2, I3, I4, I5, S1, S2, S3, S4, S5>(
+        globs: I1,
+        ignored_file_globs: I2,
+        ignored_dir_globs: I3,
+        ignored_dir_prefixes_globs: I4,
+        ignored_abs_dirs: I5,
+        case: GlobCase,
+    ) -> result::Result<Self, E>
+        where
+        E: From<globset::Error>,
+        I1: Iterator<Item = result::Result<S1, E>>,
+        I2: Iterator<Item = result::Result<S2, E>>,
+        I3: Iterator<Item = result::Result<S3, E>>,
+        I4: Iterator<Item = result::Result<S4, E>>,
+        I5: Iterator<Item = result::Result<S5, E>>,
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+        S3: AsRef<str>,
+        S4: AsRef<str>,
+        S5: AsRef<str>,
+    {
+        let mut wanted_files = GlobEntryBuilder::new();
+        let mut ignored_files = GlobEntryBuilder::new();
+        let mut ignored_dirs = GlobEntryBuilder::new();
+        let mut spec: [Vec<String>; 5] = Default::default();
 
         for x in globs {
             let y = x?;
-            let z = y.as_ref();
-            let g = mk_glob(z, true)?;
-            if glob_should_test_against_abs(z) {
-                wanted_file_abs_builder.add(g);
-            } else {
-                wanted_file_rel_builder.add(g);
-            }
+            spec[0].push(y.as_ref().to_string());
+            let (neg, z) = parse_negation(y.as_ref());
+            wanted_files.add(z, case.globs, neg, glob_should_test_against_abs(z))?;
         }
         for x in ignored_file_globs {
             let y = x?;
-            let z = y.as_ref();
-            let g = mk_glob(z, false)?;
-            if glob_should_test_against_abs(z) {
-                ignored_file_abs_builder.add(g);
-            } else {
-                ignored_file_rel_builder.add(g);
-            }
+            spec[1].push(y.as_ref().to_string());
+            let (neg, z) = parse_negation(y.as_ref());
+            ignored_files.add(z, case.ignored_files, neg, glob_should_test_against_abs(z))?;
         }
 
         {
             let mut tmp = String::new();
             for x in ignored_abs_dirs {
                 let y = x?;
-                ignored_dir_abs_builder.add(mk_glob(strip_trailing_slash(y.as_ref()), false)?);
-                tmp.clear();
+                spec[4].push(y.as_ref().to_string());
+                let (neg, z) = parse_negation(y.as_ref());
+                ignored_dirs.add(strip_trailing_slash(z), case.ignored_abs_dirs, neg, true)?;
             }
             for x in ignored_dir_globs {
                 let y = x?;
-                let z = strip_trailing_slash(y.as_ref());
+                spec[2].push(y.as_ref().to_string());
+                let (neg, z) = parse_negation(y.as_ref());
+                let z = strip_trailing_slash(z);
                 tmp.push_str("**/");
                 tmp.push_str(z);
-                let g = mk_glob(&tmp, false)?;
-                if glob_should_test_against_abs(z) {
-                    ignored_dir_abs_builder.add(g);
-                } else {
-                    ignored_dir_rel_builder.add(g);
-                }
+                ignored_dirs.add(&tmp, case.ignored_dirs, neg, glob_should_test_against_abs(z))?;
                 tmp.clear();
             }
             for x in ignored_dir_prefixes_globs {
                 let y = x?;
-                let z = y.as_ref();
+                spec[3].push(y.as_ref().to_string());
+                let (neg, z) = parse_negation(y.as_ref());
                 tmp.push_str("**/");
                 tmp.push_str(z);
                 tmp.push('*');
-                let g = mk_glob(&tmp, false)?;
-                if glob_should_test_against_abs(z) {
-                    ignored_dir_abs_builder.add(g);
-                } else {
-                    ignored_dir_rel_builder.add(g);
-                }
+                ignored_dirs.add(&tmp, case.ignored_dir_prefixes, neg, glob_should_test_against_abs(z))?;
                 tmp.clear();
             }
         }
 
-        let wanted_files_rel = wanted_file_rel_builder.build()?;
-        let wanted_files_abs = wanted_file_abs_builder.build()?;
-        let ignored_files_rel = ignored_file_rel_builder.build()?;
-        let ignored_files_abs = ignored_file_abs_builder.build()?;
-        let ignored_dirs_rel = ignored_dir_rel_builder.build()?;
-        let ignored_dirs_abs = ignored_dir_abs_builder.build()?;
-
         Ok(Ignores {
-            files: IgnoreAllow::new(
-                GlobEntry { rel: ignored_files_rel, abs: ignored_files_abs },
-                GlobEntry { rel: wanted_files_rel, abs: wanted_files_abs }
-            ),
-            ignored_dirs: GlobEntry { rel: ignored_dirs_rel, abs: ignored_dirs_abs },
+            files: IgnoreAllow::new(ignored_files.build()?, wanted_files.build()?),
+            ignored_dirs: ignored_dirs.build()?,
+            case,
+            spec,
+            git_ignore: None,
+            git_excludes_stamp: None,
+            hidden: Hidden::Include,
+            hidden_allowed: GlobSet::empty(),
         })
     }
+
+    /// Describes everything that affects what is ignored, so that equal fingerprints mean
+    /// the same entries are ignored.
+    pub fn fingerprint(&self) -> String {
+        format!(
+            "{:?} {:?} {:?} {:?} {:?}",
+            self.spec,
+            self.case,
+            self.git_ignore.is_some(),
+            self.git_excludes_stamp,
+            self.hidden
+        )
+    }
+
+    pub fn set_hidden(&mut self, hidden: Hidden) -> result::Result<(), globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        if let Hidden::ExcludeExcept(ref globs) = hidden {
+            for g in globs {
+                builder.add(mk_glob(g, false)?);
+            }
+        }
+        self.hidden_allowed = builder.build()?;
+        self.hidden = hidden;
+        Ok(())
+    }
+
+    /// Whether traversal of `root` would report file at `path` within it as far as globs
+    /// and hidden-files policy are concerned. Git ignore rules are not consulted.
+    pub fn is_file_wanted(&self, root: &Path, path: &Path) -> bool {
+        let rel = match path.strip_prefix(root) {
+            Ok(x) => x,
+            Err(_) => return false,
+        };
+        let mut dir = root.to_path_buf();
+        let mut pruned = false;
+        for c in rel.parent().into_iter().flat_map(Path::components) {
+            let name = c.as_os_str();
+            dir.push(name);
+            if self.is_hidden_excluded(name) {
+                return false;
+            }
+            match self.ignored_dirs.dir_pruning(pruned, name, &dir) {
+                Some(x) => pruned = x,
+                None => return false,
+            }
+        }
+        path.file_name().is_some_and(|name| {
+            !self.is_hidden_excluded(name) && (!pruned || self.ignored_dirs.is_reincluded(name, path))
+        }) && self.files.is_path_match(path)
+    }
+
+    fn is_hidden_excluded(&self, name: &std::ffi::OsStr) -> bool {
+        let is_hidden = || name.to_string_lossy().starts_with('.');
+        match self.hidden {
+            Hidden::Include => false,
+            Hidden::Exclude => is_hidden(),
+            Hidden::ExcludeExcept(_) => is_hidden() && !self.hidden_allowed.is_match(name),
+        }
+    }
+
+    /// Additionally honor `.gitignore`, `.ignore`, `.git/info/exclude` and global
+    /// `core.excludesFile` the way git and ripgrep do. Repository data in `.git`
+    /// directories is skipped as well.
+    pub fn enable_git_ignore(&mut self) {
+        // Same file that `Gitignore::global` reads.
+        self.git_excludes_stamp = ignore::gitignore::gitconfig_excludes_path().map(|path| {
+            let mtime = std::fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+            (path, mtime)
+        });
+        // Global excludes file is optional so don’t fail when it’s missing or malformed.
+        let (global, _err) = Gitignore::global();
+        self.git_ignore = Some(global);
+    }
+
+    fn is_git_ignored(&self, state: &DirState, path: &Path, is_dir: bool) -> bool {
+        if is_dir && self.git_ignore.is_some() && path.file_name().is_some_and(|x| x == ".git") {
+            return true;
+        }
+        match (&self.git_ignore, &state.git_ignores) {
+            (Some(global), Some(rules)) => rules.is_ignored(global, path, is_dir),
+            _ => false,
+        }
+    }
+}
+
+/// Rules from `.ignore`, `.gitignore` and `.git/info/exclude` files of a directory
+/// along with rules of all its parents.
+#[derive(Debug)]
+pub struct GitIgnores {
+    parent: Option<Arc<GitIgnores>>,
+    /// Rules of a single directory in order of decreasing precedence.
+    rules: Vec<Gitignore>,
+    /// Whether the directory is within a git repository - `.gitignore` files don’t apply otherwise.
+    in_repo: bool,
+    /// Changes whenever rules of this directory or any of its parents change.
+    stamp: u64,
+}
+
+/// Rules from `file` along with its modification time.
+fn read_ignore_file(dir: &Path, file: &Path) -> io::Result<Option<(Gitignore, Option<SystemTime>)>> {
+    let mtime = match std::fs::metadata(file) {
+        Ok(meta) if meta.is_file() => meta.modified().ok(),
+        _ => return Ok(None),
+    };
+    let mut builder = GitignoreBuilder::new(dir);
+    // Malformed lines are skipped, just like git does, but failure to read is an error.
+    if let Some(err) = builder.add(file) {
+        if let Some(io_err) = err.io_error() {
+            return Err(io::Error::new(io_err.kind(), io_err.to_string()));
+        }
+    }
+    let res = builder.build().map_err(io::Error::other)?;
+    Ok(if res.is_empty() { None } else { Some((res, mtime)) })
+}
+
+impl GitIgnores {
+    /// Collect rules of `dir` on top of the rules of its parent. Reuses the parent if
+    /// `dir` doesn’t add anything. Files that can’t be read are recorded in `errors`
+    /// and their rules don’t apply.
+    fn for_dir(parent: Option<&Arc<GitIgnores>>, dir: &Path, errors: &mut Vec<EntryError>) -> Arc<GitIgnores> {
+        let is_repo_root = dir.join(".git").exists();
+        let in_repo = is_repo_root || parent.is_some_and(|p| p.in_repo);
+
+        let mut files = vec![dir.join(".ignore")];
+        if in_repo {
+            files.push(dir.join(".gitignore"));
+        }
+        if is_repo_root {
+            files.push(dir.join(".git").join("info").join("exclude"));
+        }
+
+        let mut hasher = FnvHasher::default();
+        parent.map_or(0, |p| p.stamp).hash(&mut hasher);
+        in_repo.hash(&mut hasher);
+        let mut rules = Vec::new();
+        for file in files {
+            match read_ignore_file(dir, &file) {
+                Ok(Some((r, mtime))) => {
+                    (&file, mtime).hash(&mut hasher);
+                    rules.push(r);
+                }
+                Ok(None) => (),
+                Err(err) => errors.push(EntryError { path: file, err }),
+            }
+        }
+        let stamp = hasher.finish();
+
+        match parent {
+            Some(p) if rules.is_empty() && p.in_repo == in_repo => p.clone(),
+            _ => Arc::new(GitIgnores { parent: parent.cloned(), rules, in_repo, stamp }),
+        }
+    }
+
+    /// Rules that apply to a traversal root from all of its parent directories.
+    fn for_parents_of(root: &Path, errors: &mut Vec<EntryError>) -> Arc<GitIgnores> {
+        let mut res = Arc::new(GitIgnores { parent: None, rules: Vec::new(), in_repo: false, stamp: 0 });
+        let mut parents: Vec<&Path> = root
+            .ancestors()
+            .skip(1)
+            .filter(|p| !p.as_os_str().is_empty())
+            .collect();
+        parents.reverse();
+        for p in parents {
+            res = GitIgnores::for_dir(Some(&res), p, errors);
+        }
+        res
+    }
+
+    /// Closest directory decides, so that `!pattern` in a subdirectory can re-include
+    /// what its parent ignored.
+    fn is_ignored(&self, global: &Gitignore, path: &Path, is_dir: bool) -> bool {
+        let mut level = Some(self);
+        while let Some(l) = level {
+            for r in &l.rules {
+                match r.matched(path, is_dir) {
+                    ignore::Match::None => (),
+                    ignore::Match::Ignore(_) => return true,
+                    ignore::Match::Whitelist(_) => return false,
+                }
+            }
+            level = l.parent.as_deref();
+        }
+        // Global excludes are part of git configuration so they apply only within repositories.
+        self.in_repo && global.matched(path, is_dir).is_ignore()
+    }
+}
+
+#[cfg(target_family = "unix")]
+type DirKey = (u64, u64);
+
+#[cfg(target_family = "windows")]
+type DirKey = PathBuf;
+
+/// Identity of a directory that is the same regardless of which symlink leads to it.
+#[cfg(target_family = "unix")]
+fn dir_key(_path: &Path, meta: &std::fs::Metadata) -> io::Result<DirKey> {
+    use std::os::unix::fs::MetadataExt;
+    Ok((meta.dev(), meta.ino()))
+}
+
+#[cfg(target_family = "windows")]
+fn dir_key(path: &Path, _meta: &std::fs::Metadata) -> io::Result<DirKey> {
+    path.canonicalize()
+}
+
+/// Identity of the filesystem an entry resides on.
+#[cfg(target_family = "unix")]
+fn device_id(meta: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.dev())
+}
+
+#[cfg(target_family = "windows")]
+fn device_id(_meta: &std::fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Directories on the way from traversal root, used to detect symlink cycles.
+#[derive(Debug)]
+pub struct Ancestors {
+    key: DirKey,
+    parent: Option<Arc<Ancestors>>,
+}
+
+impl Ancestors {
+    fn contains(this: &Option<Arc<Ancestors>>, key: &DirKey) -> bool {
+        let mut curr = this.as_deref();
+        while let Some(x) = curr {
+            if x.key == *key {
+                return true;
+            }
+            curr = x.parent.as_deref();
+        }
+        false
+    }
+}
+
+/// Per-directory traversal state that is inherited by subdirectories.
+#[derive(Clone, Debug)]
+pub struct DirState {
+    /// Rules from `.gitignore` files of parent directories, if they’re honored.
+    pub git_ignores: Option<Arc<GitIgnores>>,
+    /// Directories being visited, tracked only when following symlinks.
+    pub ancestors: Option<Arc<Ancestors>>,
+    /// Number of directories between the root and this one, 0 for the root itself.
+    pub depth: usize,
+    /// Filesystem of the root, known only when traversal stays within it.
+    pub device: Option<u64>,
+    /// Whether directory is ignored and only traversed for entries that negated globs
+    /// re-include.
+    pub pruned: bool,
+    /// Metadata of the directory itself if its parent already fetched it, so that traversal
+    /// doesn’t have to fetch it again.
+    pub meta: Option<std::fs::Metadata>,
+}
+
+impl DirState {
+    /// Ignore files of parents of `root` that can’t be read are recorded in `errors`.
+    pub fn for_root(root: &Path, ignores: &Ignores, errors: &mut Vec<EntryError>) -> DirState {
+        let git_ignores = ignores.git_ignore.as_ref().map(|_| GitIgnores::for_parents_of(root, errors));
+        DirState { git_ignores, ancestors: None, depth: 0, device: None, pruned: false, meta: None }
+    }
+
+    /// State that traversal from `root` would have arrived with to `dir`.
+    pub fn for_subdir(root: &Path, dir: &Path, ignores: &Ignores, errors: &mut Vec<EntryError>) -> DirState {
+        let mut res = DirState::for_root(dir, ignores, errors);
+        if let Ok(rel) = dir.strip_prefix(root) {
+            res.depth = rel.components().count();
+            let mut path = root.to_path_buf();
+            for c in rel.components() {
+                path.push(c);
+                res.pruned = ignores.ignored_dirs.dir_pruning(res.pruned, c.as_os_str(), &path).unwrap_or(res.pruned);
+            }
+        }
+        res
+    }
 }
 
 pub trait Root: Clone + Send + 'static {
@@ -248,38 +947,55 @@ impl Root for Arc<PathBuf> {
 }
 
 // Define a function callable by Lisp.
-pub fn find_rec<'a, Str, Iter, Consume, OrigRoot, Res, HandleFile, InitThread, State>(
+/// Traverse `roots` in parallel and pass results produced by `handle_file` and `handle_error`
+/// in worker threads to `consume` on the calling thread. `should_quit` is polled periodically
+/// on the calling thread, once it returns true workers are stopped and `Interrupted` is returned.
+#[allow(clippy::too_many_arguments)]
+pub fn find_rec<'a, Str, Iter, Consume, ShouldQuit, OrigRoot, Res, HandleFile, HandleError, InitThread, State>(
     roots: Iter,
     roots_count: usize,
     ignores: &Ignores,
+    opts: &Opts,
     init_thread: InitThread,
     handle_file: HandleFile,
+    handle_error: HandleError,
     mut consume: Consume,
+    should_quit: ShouldQuit,
 ) -> Result<()>
     where
     Str: AsRef<str>,
     Iter: Iterator<Item = Result<Str>>,
     Consume: FnMut(Res) -> Result<()>,
+    ShouldQuit: FnMut() -> bool,
     OrigRoot: Root + std::fmt::Debug,
     Res: Send + 'static,
-    HandleFile: FnMut(&mut State, OrigRoot, PathBuf, &mut mpsc::SyncSender<Res>) -> Result<()> + Send + Clone,
+    HandleFile: FnMut(&mut State, OrigRoot, PathBuf, Kind, &mut mpsc::SyncSender<Res>) -> Result<()> + Send + Clone,
+    HandleError: FnMut(OrigRoot, EntryError, &mut mpsc::SyncSender<Res>) -> Result<()> + Send + Clone,
     InitThread: FnMut() -> Result<State> + Send + Clone,
 {
-    let (report_result, receive_result) = mpsc::sync_channel(2 * THREADS);
+    let threads = opts.threads.get();
 
-    let tasks_queue = ArrayQueue::new((10 * THREADS).max(roots_count));
+    let (report_result, receive_result) = mpsc::sync_channel(2 * threads);
 
+    let tasks_queue = ArrayQueue::new((10 * threads).max(roots_count));
+
+    // Problems with ignore files of roots’ parents, reported by the main worker.
+    let mut root_errors: Vec<(OrigRoot, EntryError)> = Vec::new();
     for r in roots {
         let path = std::path::PathBuf::from(std::ffi::OsString::from(r?.as_ref()));
-        if !ignores.ignored_dirs.abs.is_match(&path) && !ignores.ignored_dirs.rel.is_match(&path) {
-            tasks_queue.push((Root::from_path(&path), path)).expect("Task queue should have enough size to hold initial set of roots");
+        if !ignores.ignored_dirs.is_path_match(&path) {
+            let orig_root = OrigRoot::from_path(&path);
+            let mut errors = Vec::new();
+            let state = DirState::for_root(&path, ignores, &mut errors);
+            root_errors.extend(errors.into_iter().map(|e| (orig_root.clone(), e)));
+            tasks_queue.push((orig_root, path, state)).expect("Task queue should have enough size to hold initial set of roots");
         }
     }
 
-    let tasks = &tasks_queue;
-
-    let barr = Barrier::new(THREADS);
-    let barr_ref = &barr;
+    let barr = Barrier::new(threads);
+    let cancelled = AtomicBool::new(false);
+    let shared = Shared { barr: &barr, cancelled: &cancelled, tasks: &tasks_queue, ignores, opts };
+    let shared_ref = &shared;
 
     crossbeam::scope(
         move |s| -> result::Result<_, _> {
@@ -289,78 +1005,119 @@ pub fn find_rec<'a, Str, Iter, Consume, OrigRoot, Res, HandleFile, InitThread, S
             let main_id: Handle = {
                 let private_report_result = report_result.clone();
                 let mut handle = handle_file.clone();
+                let mut handle_err = handle_error.clone();
                 let mut init = init_thread.clone();
                 s.spawn(
                     move |_| {
                         let mut s = init()?;
                         process_main(
-                            barr_ref,
-                            tasks,
+                            shared_ref,
+                            root_errors,
                             private_report_result,
-                            ignores,
-                            |root, x, chan| handle(&mut s, root, x, chan),
+                            |root, x, kind, chan| handle(&mut s, root, x, kind, chan),
+                            |root, err, chan| handle_err(root, err, chan),
                         )
                     }
                 )
             };
 
-            const INIT: MaybeUninit<Handle> = MaybeUninit::uninit();
-            let mut handles: [_; THREADS] = [INIT; THREADS];
-            handles[0] = MaybeUninit::new(main_id);
+            let mut handles: Vec<Handle> = Vec::with_capacity(threads);
+            handles.push(main_id);
 
-            for i in 1..THREADS {
+            for _ in 1..threads {
                 let private_report_result = report_result.clone();
                 let mut handle = handle_file.clone();
+                let mut handle_err = handle_error.clone();
                 let mut init = init_thread.clone();
                 let id: Handle = s.spawn(
                     move |_| -> result::Result<_, _> {
                         let mut s = init()?;
                         process_child(
-                            barr_ref,
-                            tasks,
+                            shared_ref,
                             private_report_result,
-                            ignores,
-                            |root, x, chan| handle(&mut s, root, x, chan),
+                            |root, x, kind, chan| handle(&mut s, root, x, kind, chan),
+                            |root, err, chan| handle_err(root, err, chan),
                         )
                     }
                 );
-                handles[i] = MaybeUninit::new(id);
+                handles.push(id);
             }
 
             std::mem::drop(report_result);
-            while let Ok(x) = receive_result.recv() {
-               consume(x)?
+
+            let mut quit = QuitPoller::new(should_quit);
+            let mut res = Ok(());
+            loop {
+                match receive_result.recv_timeout(QUIT_POLL_INTERVAL) {
+                    Ok(x) => res = consume(x),
+                    Err(mpsc::RecvTimeoutError::Timeout) => (),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+                if res.is_ok() && quit.poll() {
+                    res = Err(anyhow::Error::new(Interrupted));
+                }
+                if res.is_err() {
+                    shared_ref.cancelled.store(true, Ordering::Relaxed);
+                    // Unblock workers waiting for space in the channel so that they can stop.
+                    while receive_result.recv().is_ok() {}
+                    break;
+                }
             }
 
             for h in handles {
-                unsafe {
-                    h.assume_init().join().unwrap().unwrap();
+                match h.join() {
+                    Ok(Ok(())) => (),
+                    Ok(Err(err)) => {
+                        if res.is_ok() {
+                            res = Err(err);
+                        }
+                    }
+                    Err(panic) => std::panic::resume_unwind(panic),
                 }
             }
 
-            Ok(())
+            res
         }
     ).unwrap()
 }
 
-fn process_main<R, A, F, E>(
-    barr: &Barrier,
-    tasks: &ArrayQueue<(R, PathBuf)>,
+/// Traversal state of `find_rec` that all workers share.
+struct Shared<'a, R> {
+    barr: &'a Barrier,
+    cancelled: &'a AtomicBool,
+    tasks: &'a ArrayQueue<(R, PathBuf, DirState)>,
+    ignores: &'a Ignores,
+    opts: &'a Opts,
+}
+
+fn process_main<R, A, F, G, E>(
+    shared: &Shared<R>,
+    root_errors: Vec<(R, EntryError)>,
     mut report_result: mpsc::SyncSender<A>,
-    ignores: &Ignores,
     mut handle_file: F,
+    mut handle_error: G,
 ) -> result::Result<(), E>
     where
     A: Send + 'static,
     R: Root,
-    F: FnMut(R, PathBuf, &mut mpsc::SyncSender<A>) -> result::Result<(), E>,
+    F: FnMut(R, PathBuf, Kind, &mut mpsc::SyncSender<A>) -> result::Result<(), E>,
+    G: FnMut(R, EntryError, &mut mpsc::SyncSender<A>) -> result::Result<(), E>,
     E: From<std::io::Error>,
 {
+    let Shared { barr, cancelled, tasks, ignores, opts } = *shared;
+    for (orig_root, err) in root_errors {
+        if let Err(err) = handle_error(orig_root, err, &mut report_result) {
+            cancelled.store(true, Ordering::Relaxed);
+            // Children must not be left waiting.
+            barr.wait();
+            return Err(err);
+        }
+    }
     let mut children_awoken = false;
 
-    let mut local_queue: Vec<(R, PathBuf)> = Vec::new();
-    loop {
-        let (orig_root, root) = match local_queue.pop() {
+    let mut local_queue: Vec<(R, PathBuf, DirState)> = Vec::new();
+    while !cancelled.load(Ordering::Relaxed) {
+        let (orig_root, root, state) = match local_queue.pop() {
             Some(x) =>
                 match tasks.push(x) {
                     Ok(()) => continue,
@@ -372,15 +1129,29 @@ fn process_main<R, A, F, E>(
             },
         };
 
-        visit_dir(
+        let res = visit_dir(
             root,
+            &state,
             ignores,
-            |path| match tasks.push((orig_root.clone(), path)) {
+            opts,
+            |path, child_state| match tasks.push((orig_root.clone(), path, child_state)) {
                 Ok(()) => (),
                 Err(task) => local_queue.push(task),
             },
-            |x| { handle_file(orig_root.clone(), x, &mut report_result) },
-        )?;
+            |x| match x {
+                _ if cancelled.load(Ordering::Relaxed) => Ok(()),
+                Ok((path, kind)) => handle_file(orig_root.clone(), path, kind, &mut report_result),
+                Err(err) => handle_error(orig_root.clone(), err, &mut report_result),
+            },
+        );
+        if let Err(err) = res {
+            cancelled.store(true, Ordering::Relaxed);
+            // Children must not be left waiting.
+            if !children_awoken {
+                barr.wait();
+            }
+            return Err(err);
+        }
 
         if !children_awoken && tasks.is_full() {
             // Wake up children.
@@ -395,24 +1166,25 @@ fn process_main<R, A, F, E>(
     Ok(())
 }
 
-fn process_child<R, A, F, E>(
-    barr: &Barrier,
-    tasks: &ArrayQueue<(R, PathBuf)>,
+fn process_child<R, A, F, G, E>(
+    shared: &Shared<R>,
     mut report_result: mpsc::SyncSender<A>,
-    ignores: &Ignores,
     mut handle_file: F,
+    mut handle_error: G,
 ) -> result::Result<(), E>
     where
     A: Send + 'static,
     R: Root,
-    F: FnMut(R, PathBuf, &mut mpsc::SyncSender<A>) -> result::Result<(), E>,
+    F: FnMut(R, PathBuf, Kind, &mut mpsc::SyncSender<A>) -> result::Result<(), E>,
+    G: FnMut(R, EntryError, &mut mpsc::SyncSender<A>) -> result::Result<(), E>,
     E: From<std::io::Error>,
 {
+    let Shared { barr, cancelled, tasks, ignores, opts } = *shared;
     barr.wait();
 
-    let mut local_queue: Vec<(R, PathBuf)> = Vec::new();
-    loop {
-        let (orig_root, root) = match local_queue.pop() {
+    let mut local_queue: Vec<(R, PathBuf, DirState)> = Vec::new();
+    while !cancelled.load(Ordering::Relaxed) {
+        let (orig_root, root, state) = match local_queue.pop() {
             Some(x) =>
                 match tasks.push(x) {
                     Ok(()) => continue,
@@ -424,45 +1196,684 @@ fn process_child<R, A, F, E>(
             },
         };
 
-        visit_dir(
+        let res = visit_dir(
             root,
+            &state,
             ignores,
-            |path| match tasks.push((orig_root.clone(), path)) {
+            opts,
+            |path, child_state| match tasks.push((orig_root.clone(), path, child_state)) {
                 Ok(()) => (),
                 Err(task) => local_queue.push(task),
             },
-            |x| { handle_file(orig_root.clone(), x, &mut report_result) },
-        )?;
+            |x| match x {
+                _ if cancelled.load(Ordering::Relaxed) => Ok(()),
+                Ok((path, kind)) => handle_file(orig_root.clone(), path, kind, &mut report_result),
+                Err(err) => handle_error(orig_root.clone(), err, &mut report_result),
+            },
+        );
+        if let Err(err) = res {
+            cancelled.store(true, Ordering::Relaxed);
+            return Err(err);
+        }
     }
     Ok(())
 }
 
+/// Report entries within `root` and schedule its subdirectories. I/O problems with `root` or
+/// any of its entries, like permission errors or broken symlinks, are passed to `record_file`
+/// as errors and don’t stop the traversal.
 pub fn visit_dir<D, F, E>(
     root: PathBuf,
+    parent_state: &DirState,
     ignores: &Ignores,
+    opts: &Opts,
     mut record_dir: D,
     mut record_file: F,
 ) -> result::Result<(), E>
     where
-    D: FnMut(PathBuf) -> (),
-    F: FnMut(PathBuf) -> result::Result<(), E>,
+    D: FnMut(PathBuf, DirState),
+    F: FnMut(result::Result<(PathBuf, Kind), EntryError>) -> result::Result<(), E>,
     E: From<std::io::Error>,
 {
-    for entry in std::fs::read_dir(root)? {
-        let entry = entry?;
-        let typ = entry.file_type()?;
+    let mut state = parent_state.clone();
+    // Metadata is only needed to detect loops, stay on one filesystem or validate cached entries.
+    let needs_meta = opts.follow_symlinks || opts.same_filesystem || opts.cache.is_some();
+    let meta = match state.meta.take() {
+        Some(x) => Some(x),
+        None if needs_meta => match std::fs::metadata(&root) {
+            Ok(x) => Some(x),
+            Err(err) => return record_file(Err(EntryError { path: root, err })),
+        },
+        None => None,
+    };
+    if let Some(ref x) = parent_state.git_ignores {
+        let mut errors = Vec::new();
+        state.git_ignores = Some(GitIgnores::for_dir(Some(x), &root, &mut errors));
+        for err in errors {
+            record_file(Err(err))?;
+        }
+    }
+
+    if let (true, Some(meta)) = (opts.follow_symlinks, &meta) {
+        let key = match dir_key(&root, meta) {
+            Ok(x) => x,
+            Err(err) => return record_file(Err(EntryError { path: root, err })),
+        };
+        if Ancestors::contains(&parent_state.ancestors, &key) {
+            let err = io::Error::other("Filesystem loop detected");
+            return record_file(Err(EntryError { path: root, err }));
+        }
+        state.ancestors = Some(Arc::new(Ancestors { key, parent: parent_state.ancestors.clone() }));
+    }
+    if let (true, Some(meta)) = (opts.same_filesystem, &meta) {
+        let device = device_id(meta);
+        match state.device {
+            None => state.device = device,
+            // Directory may have become a mount point since its parent was cached.
+            Some(x) if device != Some(x) => {
+                let err = io::Error::other("Skipped mount point of another filesystem");
+                return record_file(Err(EntryError { path: root, err }));
+            }
+            Some(_) => (),
+        }
+    }
+
+    let depth = state.depth + 1;
+    let report = depth >= opts.min_depth && opts.max_depth.is_none_or(|m| depth <= m);
+    let report_files = report && opts.mode.reports(Kind::File);
+    let report_dirs = report && opts.mode.reports(Kind::Dir);
+    // Whether some subdirectories may be pruned rather than skipped.
+    let may_prune = !ignores.ignored_dirs.reinclude_below.is_empty();
+    let descend = opts.max_depth.is_none_or(|m| depth < m);
+
+    let cache = opts.cache.as_deref().and_then(|cache| {
+        let read_at = SystemTime::now();
+        let mtime = meta.as_ref()?.modified().ok()?;
+        let rules = state.git_ignores.as_ref().map_or(0, |x| x.stamp);
+        Some((cache, dir_cache::Stamp { mtime, rules }, read_at))
+    });
+    if let Some((cache, stamp, _)) = cache {
+        if let Some(entries) = cache.lookup(&root, &stamp) {
+            for e in &entries {
+                let path = root.join(&e.name);
+                if e.report {
+                    record_file(Ok((path.clone(), e.kind)))?;
+                }
+                if e.descend {
+                    let pruned = may_prune && ignores.ignored_dirs.dir_pruning(state.pruned, &e.name, &path).unwrap_or(true);
+                    record_dir(path, DirState { depth, pruned, meta: None, ..state.clone() });
+                }
+            }
+            cache.store(root, stamp, entries);
+            return Ok(());
+        }
+    }
+    // Entries to cache, if directory is read without errors.
+    let mut cached_entries = Vec::new();
+    let mut clean = true;
+    let mut record_file = |x: result::Result<(PathBuf, Kind), EntryError>| {
+        clean &= x.is_ok();
+        record_file(x)
+    };
+
+    let entries = match std::fs::read_dir(&root) {
+        Ok(x) => x,
+        Err(err) => return record_file(Err(EntryError { path: root, err })),
+    };
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(x) => x,
+            Err(err) => {
+                record_file(Err(EntryError { path: root.clone(), err }))?;
+                continue;
+            }
+        };
+        if ignores.is_hidden_excluded(&entry.file_name()) {
+            continue;
+        }
+        let mut typ = match entry.file_type() {
+            Ok(x) => x,
+            Err(err) => {
+                record_file(Err(EntryError { path: entry.path(), err }))?;
+                continue;
+            }
+        };
+        // Metadata of the symlink target, reused for subdirectories.
+        let mut target_meta = None;
+        if typ.is_symlink() && opts.follow_symlinks {
+            match std::fs::metadata(entry.path()) {
+                Ok(meta) => {
+                    typ = meta.file_type();
+                    target_meta = Some(meta);
+                }
+                Err(err) => {
+                    record_file(Err(EntryError { path: entry.path(), err }))?;
+                    continue;
+                }
+            }
+        }
         let mut tmp = None;
         if typ.is_file() {
-            if ignores.files.is_match(&entry, &mut tmp) {
+            if report_files && ignores.files.is_match(&entry, &mut tmp) {
                 let path = tmp.unwrap_or_else(|| entry.path());
-                record_file(path)?;
+                if state.pruned && !ignores.ignored_dirs.is_reincluded(&entry.file_name(), &path) {
+                    continue;
+                }
+                if !ignores.is_git_ignored(&state, &path, false) {
+                    if opts.filter.is_active() {
+                        match std::fs::metadata(&path).and_then(|meta| opts.filter.matches(&path, &meta)) {
+                            Ok(true) => (),
+                            Ok(false) => continue,
+                            Err(err) => {
+                                record_file(Err(EntryError { path, err }))?;
+                                continue;
+                            }
+                        }
+                    }
+                    if cache.is_some() {
+                        cached_entries.push(dir_cache::Entry { name: entry.file_name(), kind: Kind::File, report: true, descend: false });
+                    }
+                    record_file(Ok((path, Kind::File)))?;
+                }
             }
         } else if typ.is_dir() {
-            if !ignores.ignored_dirs.is_match(&entry, &mut tmp) {
-                let path = tmp.unwrap_or_else(|| entry.path());
-                record_dir(path);
+            if !descend && !report_dirs {
+                continue;
             }
+            let pruned = if !state.pruned && !ignores.ignored_dirs.is_match(&entry, &mut tmp) {
+                false
+            } else if may_prune {
+                let path = tmp.get_or_insert_with(|| entry.path());
+                match ignores.ignored_dirs.dir_pruning(state.pruned, &entry.file_name(), path) {
+                    Some(x) => x,
+                    None => continue,
+                }
+            } else {
+                continue;
+            };
+            if pruned && !descend {
+                continue;
+            }
+            let path = tmp.unwrap_or_else(|| entry.path());
+            if !ignores.is_git_ignored(&state, &path, true) {
+                let meta = match target_meta {
+                    Some(x) => Some(x),
+                    None if needs_meta => match std::fs::metadata(&path) {
+                        Ok(x) => Some(x),
+                        Err(err) => {
+                            record_file(Err(EntryError { path, err }))?;
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                if let (Some(device), Some(meta)) = (state.device, &meta) {
+                    if device_id(meta) != Some(device) {
+                        let err = io::Error::other("Skipped mount point of another filesystem");
+                        record_file(Err(EntryError { path, err }))?;
+                        continue;
+                    }
+                }
+                let report_dir = report_dirs && !pruned;
+                if cache.is_some() {
+                    cached_entries.push(dir_cache::Entry { name: entry.file_name(), kind: Kind::Dir, report: report_dir, descend });
+                }
+                if report_dir {
+                    record_file(Ok((path.clone(), Kind::Dir)))?;
+                }
+                if descend {
+                    record_dir(path, DirState { depth, pruned, meta, ..state.clone() });
+                }
+            }
+        }
+    }
+    if let Some((cache, stamp, read_at)) = cache {
+        if clean && stamp.is_settled(read_at) {
+            cache.store(root, stamp, cached_entries);
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    fn ignores(lists: [&[&str]; 5], case: GlobCase) -> Ignores {
+        let it = |xs: &[&str]| xs.iter().map(|x| Ok(x.to_string())).collect::<Vec<anyhow::Result<String>>>().into_iter();
+        Ignores::new_with_case(it(lists[0]), it(lists[1]), it(lists[2]), it(lists[3]), it(lists[4]), case).unwrap()
+    }
+
+    fn ignored_dirs(globs: &[&str]) -> Ignores {
+        ignores([&["*"], &[], globs, &[], &[]], GlobCase::default())
+    }
+
+    fn entry(globs: &[&str]) -> GlobEntry {
+        let mut b = GlobEntryBuilder::new();
+        for g in globs {
+            let (neg, z) = parse_negation(g);
+            b.add(z, false, neg, glob_should_test_against_abs(z)).unwrap();
+        }
+        b.build().unwrap()
+    }
+
+    fn decide(e: &GlobEntry, path: &str) -> bool {
+        let path = Path::new(path);
+        let name = globset::Candidate::new(path.file_name().unwrap());
+        e.decide(Some(&name), Some(&globset::Candidate::new(path)))
+    }
+
+    /// Directory tree in a temporary directory that is removed once the test finishes.
+    pub(crate) struct TempTree(pub PathBuf);
+
+    impl TempTree {
+        pub(crate) fn new(name: &str, files: &[&str]) -> Self {
+            let root = std::env::temp_dir().join(format!("find-test-{}-{}", std::process::id(), name));
+            for f in files {
+                let path = root.join(f);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, "").unwrap();
+            }
+            TempTree(root)
+        }
+
+        fn write(&self, file: &str, contents: &[u8]) {
+            std::fs::write(self.0.join(file), contents).unwrap();
+        }
+
+        fn rel(&self, path: &Path) -> String {
+            path.strip_prefix(&self.0).unwrap().to_string_lossy().into_owned()
+        }
+
+        /// Files found by sequential traversal, relative to the root.
+        fn find(&self, ignores: &Ignores) -> Vec<String> {
+            let (files, errors) = self.find_from("", ignores, &Opts::default());
+            assert_eq!(errors, Vec::<String>::new());
+            files
+        }
+
+        /// Entries and errors found by sequential traversal from `start`, relative to the root.
+        fn find_from(&self, start: &str, ignores: &Ignores, opts: &Opts) -> (Vec<String>, Vec<String>) {
+            let start = self.0.join(start);
+            let mut errors = Vec::new();
+            let state = DirState::for_root(&start, ignores, &mut errors);
+            let mut errors: Vec<String> = errors.iter().map(|e| self.rel(&e.path)).collect();
+            let mut queue = vec![(start, state)];
+            let mut res = Vec::new();
+            while let Some((dir, state)) = queue.pop() {
+                let r: io::Result<()> = visit_dir(
+                    dir,
+                    &state,
+                    ignores,
+                    opts,
+                    |p, s| queue.push((p, s)),
+                    |x| {
+                        match x {
+                            Ok((p, _)) => res.push(self.rel(&p)),
+                            Err(e) => errors.push(self.rel(&e.path)),
+                        }
+                        Ok(())
+                    },
+                );
+                r.unwrap();
+            }
+            res.sort();
+            errors.sort();
+            (res, errors)
+        }
+    }
+
+    impl Drop for TempTree {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn parse_negation_prefixes() {
+        assert_eq!(parse_negation("*.rs"), (false, "*.rs"));
+        assert_eq!(parse_negation("!*.rs"), (true, "*.rs"));
+        assert_eq!(parse_negation("\\!*.rs"), (false, "!*.rs"));
+        assert_eq!(parse_negation("!!x"), (true, "!x"));
+        assert_eq!(parse_negation("a!b"), (false, "a!b"));
+    }
+
+    #[test]
+    fn decide_last_match_wins() {
+        let e = entry(&["*.rs", "!main.rs", "*main*"]);
+        assert!(decide(&e, "/src/lib.rs"));
+        assert!(decide(&e, "/src/main.rs"));
+        assert!(decide(&e, "/src/main.c"));
+        assert!(!decide(&e, "/src/lib.c"));
+
+        let e = entry(&["*.rs", "!main.rs"]);
+        assert!(!decide(&e, "/src/main.rs"));
+        assert!(!decide(&e, "/src/lib.c"));
+    }
+
+    #[test]
+    fn decide_across_rel_and_abs() {
+        // Relative and absolute globs share the order they were given in.
+        let e = entry(&["*.rs", "!**/gen/*.rs"]);
+        assert!(decide(&e, "/src/lib.rs"));
+        assert!(!decide(&e, "/src/gen/lib.rs"));
+
+        let e = entry(&["!**/gen/*.rs", "*.rs"]);
+        assert!(decide(&e, "/src/gen/lib.rs"));
+    }
+
+    #[test]
+    fn decide_without_candidates() {
+        let e = entry(&["*.rs", "!**/gen/*.rs"]);
+        let name = globset::Candidate::new("lib.rs");
+        assert!(e.decide(Some(&name), None));
+        assert!(!e.decide(None, None));
+    }
+
+    #[test]
+    fn escaped_exclamation_mark_is_literal() {
+        let e = entry(&["\\!*"]);
+        assert!(decide(&e, "/src/!foo"));
+        assert!(!decide(&e, "/src/foo"));
+    }
+
+    #[test]
+    fn case_per_list() {
+        let default = ignores([&["*.rs"], &["*.bak"], &["build"], &[], &[]], GlobCase::default());
+        assert!(default.is_file_wanted(Path::new("/r"), Path::new("/r/LIB.RS")));
+        assert!(!default.is_file_wanted(Path::new("/r"), Path::new("/r/lib.bak")));
+        assert!(default.is_file_wanted(Path::new("/r"), Path::new("/r/lib.BAK.rs")));
+        assert!(!default.is_file_wanted(Path::new("/r"), Path::new("/r/build/lib.rs")));
+        assert!(default.is_file_wanted(Path::new("/r"), Path::new("/r/BUILD/lib.rs")));
+
+        let case = GlobCase { globs: false, ignored_files: true, ignored_dirs: true, ..GlobCase::default() };
+        let flipped = ignores([&["*.rs"], &["*.bak.rs"], &["build"], &[], &[]], case);
+        assert!(!flipped.is_file_wanted(Path::new("/r"), Path::new("/r/LIB.RS")));
+        assert!(flipped.is_file_wanted(Path::new("/r"), Path::new("/r/lib.rs")));
+        assert!(!flipped.is_file_wanted(Path::new("/r"), Path::new("/r/lib.BAK.rs")));
+        assert!(!flipped.is_file_wanted(Path::new("/r"), Path::new("/r/BUILD/lib.rs")));
+    }
+
+    #[test]
+    fn may_reinclude_below() {
+        let e = entry(&["**/build", "!**/build/generated/*.hs", "!/abs/dir/keep"]);
+        assert!(e.may_reinclude_below(Path::new("/r/build")));
+        assert!(e.may_reinclude_below(Path::new("/r/build/generated")));
+        assert!(!e.may_reinclude_below(Path::new("/r/build/generated/sub")));
+        assert!(!e.may_reinclude_below(Path::new("/r/build/other")));
+        assert!(!e.may_reinclude_below(Path::new("/r/node_modules")));
+        assert!(e.may_reinclude_below(Path::new("/abs/dir")));
+        assert!(!e.may_reinclude_below(Path::new("/abs/dir/keep")));
+        assert!(!e.may_reinclude_below(Path::new("/abs/other")));
+
+        let e = entry(&["**/build", "!**/build/**/*.hs"]);
+        assert!(e.may_reinclude_below(Path::new("/r/build")));
+        assert!(e.may_reinclude_below(Path::new("/r/build/a/b")));
+        assert!(!e.may_reinclude_below(Path::new("/r/node_modules")));
+
+        // Negated relative globs only re-include entries by name.
+        assert!(!entry(&["**/build", "!generated"]).may_reinclude_below(Path::new("/r/build")));
+    }
+
+    #[test]
+    fn reinclude_within_ignored_dir() {
+        let ignores = ignored_dirs(&["build", "!build/generated/*.hs"]);
+        let root = Path::new("/r");
+        assert!(ignores.is_file_wanted(root, Path::new("/r/build/generated/Foo.hs")));
+        assert!(!ignores.is_file_wanted(root, Path::new("/r/build/generated/foo.o")));
+        assert!(!ignores.is_file_wanted(root, Path::new("/r/build/foo.hs")));
+        assert!(!ignores.is_file_wanted(root, Path::new("/r/build/other/Foo.hs")));
+        assert!(ignores.is_file_wanted(root, Path::new("/r/src/foo.o")));
+
+        let tree = TempTree::new("reinclude", &[
+            "src/Main.hs",
+            "build/foo.o",
+            "build/generated/Foo.hs",
+            "build/generated/foo.o",
+            "build/other/Bar.hs",
+        ]);
+        assert_eq!(tree.find(&ignores), ["build/generated/Foo.hs", "src/Main.hs"]);
+    }
+
+    #[test]
+    fn reinclude_dir_within_ignored_dir() {
+        let ignores = ignored_dirs(&["build", "!build/generated"]);
+        let tree = TempTree::new("reinclude-dir", &[
+            "build/foo.o",
+            "build/generated/Foo.hs",
+            "build/generated/sub/Bar.hs",
+        ]);
+        assert_eq!(tree.find(&ignores), ["build/generated/Foo.hs", "build/generated/sub/Bar.hs"]);
+        assert_eq!(
+            DirState::for_subdir(&tree.0, &tree.0.join("build/generated/sub"), &ignores, &mut Vec::new()).pruned,
+            false
+        );
+        assert!(DirState::for_subdir(&tree.0, &tree.0.join("build"), &ignores, &mut Vec::new()).pruned);
+    }
+
+    fn git_ignores(globs: &[&str]) -> Ignores {
+        let mut res = ignores([&["*"], &[], globs, &[], &[]], GlobCase::default());
+        res.enable_git_ignore();
+        res
+    }
+
+    #[test]
+    fn unreadable_ignore_file_is_reported() {
+        let tree = TempTree::new("unreadable-ignore", &["a.log", "sub/b.log", "sub/c.txt", "sub/.ignore"]);
+        // Not valid UTF-8, so reading rules fails.
+        tree.write(".ignore", b"*.txt\n\xff\n");
+        tree.write("sub/.ignore", b"*.log\n");
+        let ignores = git_ignores(&[]);
+        let opts = Opts::default();
+        // Rules of the unreadable file don’t apply but the rest of traversal goes on.
+        assert_eq!(
+            tree.find_from("sub", &ignores, &opts),
+            (vec!["sub/.ignore".to_string(), "sub/c.txt".to_string()], vec![".ignore".to_string()])
+        );
+        assert_eq!(
+            tree.find_from("", &ignores, &opts),
+            (
+                vec![".ignore".to_string(), "a.log".to_string(), "sub/.ignore".to_string(), "sub/c.txt".to_string()],
+                vec![".ignore".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn git_ignore_files() {
+        let tree = TempTree::new("git-ignore", &[
+            "repo/.git/HEAD",
+            "repo/.git/info/exclude",
+            "repo/.gitignore",
+            "repo/a.rs",
+            "repo/a.log",
+            "repo/x.tmp",
+            "repo/build/out.rs",
+            "repo/sub/.gitignore",
+            "repo/sub/b.rs",
+            "repo/sub/keep.log",
+            "repo/sub/other.log",
+            "outside/.gitignore",
+            "outside/.ignore",
+            "outside/d.txt",
+            "outside/e.md",
+        ]);
+        tree.write("repo/.git/info/exclude", b"*.tmp\n");
+        tree.write("repo/.gitignore", b"*.log\nbuild/\n!*.rs\n");
+        // Closer rules take precedence over the ones of the parent, whether they ignore or re-include.
+        tree.write("repo/sub/.gitignore", b"*.rs\n!keep.log\n");
+        // Not a repository so only `.ignore` applies.
+        tree.write("outside/.gitignore", b"*.txt\n");
+        tree.write("outside/.ignore", b"*.md\n");
+
+        assert_eq!(
+            tree.find(&git_ignores(&[])),
+            [
+                "outside/.gitignore",
+                "outside/.ignore",
+                "outside/d.txt",
+                "repo/.gitignore",
+                "repo/a.rs",
+                "repo/sub/.gitignore",
+                "repo/sub/keep.log",
+            ]
+        );
+        // Without git ignores everything is found, repository data included.
+        assert_eq!(tree.find(&ignored_dirs(&[])).len(), 15);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn symlinks() {
+        let tree = TempTree::new("symlinks", &["a/f.txt"]);
+        std::os::unix::fs::symlink("..", tree.0.join("a/loop")).unwrap();
+        std::os::unix::fs::symlink("a/f.txt", tree.0.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink("missing", tree.0.join("broken")).unwrap();
+        let ignores = ignored_dirs(&[]);
+
+        let opts = Opts::default();
+        assert_eq!(tree.find_from("", &ignores, &opts), (vec!["a/f.txt".to_string()], vec![]));
+
+        let opts = Opts { follow_symlinks: true, ..Opts::default() };
+        assert_eq!(
+            tree.find_from("", &ignores, &opts),
+            (vec!["a/f.txt".to_string(), "link.txt".to_string()], vec!["a/loop".to_string(), "broken".to_string()])
+        );
+    }
+
+    #[test]
+    fn depth_limits() {
+        let tree = TempTree::new("depth", &["a.txt", "d1/b.txt", "d1/d2/c.txt", "d1/d2/d3/e.txt"]);
+        let ignores = ignored_dirs(&[]);
+        let find = |min_depth, max_depth| tree.find_from("", &ignores, &Opts { min_depth, max_depth, ..Opts::default() }).0;
+        assert_eq!(find(0, Some(2)), ["a.txt", "d1/b.txt"]);
+        assert_eq!(find(2, None), ["d1/b.txt", "d1/d2/c.txt", "d1/d2/d3/e.txt"]);
+        assert_eq!(find(2, Some(3)), ["d1/b.txt", "d1/d2/c.txt"]);
+        assert_eq!(find(3, Some(2)), Vec::<String>::new());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn meta_filters() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tree = TempTree::new("meta-filter", &["empty.txt", "sub/big.txt", "small.txt", "old.txt", "run.sh"]);
+        tree.write("sub/big.txt", b"0123456789");
+        tree.write("small.txt", b"01");
+        tree.write("old.txt", b"0123");
+        tree.write("run.sh", b"0");
+        std::fs::set_permissions(tree.0.join("run.sh"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        std::fs::File::options().write(true).open(tree.0.join("old.txt")).unwrap().set_modified(hour_ago).unwrap();
+
+        let ignores = ignored_dirs(&[]);
+        let find = |filter| tree.find_from("", &ignores, &Opts { filter, ..Opts::default() }).0;
+        assert_eq!(find(MetaFilter { min_size: Some(2), ..MetaFilter::default() }), ["old.txt", "small.txt", "sub/big.txt"]);
+        assert_eq!(find(MetaFilter { max_size: Some(1), ..MetaFilter::default() }), ["empty.txt", "run.sh"]);
+        assert_eq!(find(MetaFilter { min_size: Some(2), max_size: Some(4), ..MetaFilter::default() }), ["old.txt", "small.txt"]);
+        let half_hour_ago = SystemTime::now() - Duration::from_secs(1800);
+        assert_eq!(find(MetaFilter { older_than: Some(half_hour_ago), ..MetaFilter::default() }), ["old.txt"]);
+        assert_eq!(
+            find(MetaFilter { newer_than: Some(half_hour_ago), ..MetaFilter::default() }),
+            ["empty.txt", "run.sh", "small.txt", "sub/big.txt"]
+        );
+        assert_eq!(find(MetaFilter { executable: true, ..MetaFilter::default() }), ["run.sh"]);
+        assert_eq!(find(MetaFilter { empty: true, ..MetaFilter::default() }), ["empty.txt"]);
+    }
+
+    #[test]
+    fn listing_modes() {
+        let tree = TempTree::new("modes", &["a.txt", "src/b.rs", "src/util/c.rs", "target/debug/d.o"]);
+        let ignores = ignored_dirs(&["target"]);
+        let find = |mode| tree.find_from("", &ignores, &Opts { mode, ..Opts::default() }).0;
+        assert_eq!(find(Mode::Files), ["a.txt", "src/b.rs", "src/util/c.rs"]);
+        assert_eq!(find(Mode::Dirs), ["src", "src/util"]);
+        assert_eq!(find(Mode::All), ["a.txt", "src", "src/b.rs", "src/util", "src/util/c.rs"]);
+    }
+
+    #[test]
+    fn hidden_entries() {
+        let tree = TempTree::new("hidden", &[
+            ".dir-locals.el",
+            ".env",
+            "a.txt",
+            ".github/workflows/ci.yml",
+            ".cache/x.txt",
+            "src/.hidden.rs",
+            "src/b.rs",
+        ]);
+        let find = |hidden| {
+            let mut ignores = ignored_dirs(&[]);
+            ignores.set_hidden(hidden).unwrap();
+            tree.find(&ignores)
+        };
+        assert_eq!(find(Hidden::Include).len(), 7);
+        assert_eq!(find(Hidden::Exclude), ["a.txt", "src/b.rs"]);
+        assert_eq!(
+            find(Hidden::ExcludeExcept(vec![".github".to_string(), ".dir-locals.el".to_string()])),
+            [".dir-locals.el", ".github/workflows/ci.yml", "a.txt", "src/b.rs"]
+        );
+    }
+
+    #[test]
+    fn reused_ignores() {
+        let tree = TempTree::new("reused-ignores", &["repo/.git/HEAD", "repo/.gitignore", "repo/a.rs", "repo/b.log", "other/c.log"]);
+        tree.write("repo/.gitignore", b"*.log\n");
+        let ignores = Arc::new(git_ignores(&[]));
+        let find_rec_paths = |ignores: &Ignores| {
+            let root = tree.0.to_str().unwrap();
+            let mut res = Vec::new();
+            find_rec(
+                std::iter::once(Ok(root)),
+                1,
+                ignores,
+                &Opts::default(),
+                || Ok(()),
+                |_, _root: Arc<PathBuf>, p, _kind, chan| chan.send(p).map_err(anyhow::Error::new),
+                |_root, err, _chan| Err(anyhow::Error::new(err.err)),
+                |p: PathBuf| {
+                    res.push(tree.rel(&p));
+                    Ok(())
+                },
+                || false,
+            ).unwrap();
+            res.sort();
+            res
+        };
+
+        let expected = ["other/c.log", "repo/.gitignore", "repo/a.rs"];
+        assert_eq!(find_rec_paths(&ignores), expected);
+        assert_eq!(tree.find(&ignores), expected);
+        // Compiled globs are shared but ignore files are read anew by each traversal.
+        tree.write("repo/.gitignore", b"*.rs\n");
+        let fingerprint = ignores.fingerprint();
+        assert_eq!(find_rec_paths(&ignores), ["other/c.log", "repo/.gitignore", "repo/b.log"]);
+        assert_eq!(tree.find(&ignores), ["other/c.log", "repo/.gitignore", "repo/b.log"]);
+        assert_eq!(ignores.fingerprint(), fingerprint);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn same_filesystem() {
+        let tree = TempTree::new("same-filesystem", &["a/b.txt"]);
+        std::os::unix::fs::symlink("/proc", tree.0.join("proc")).unwrap();
+        let ignores = ignored_dirs(&[]);
+        let opts = Opts { follow_symlinks: true, same_filesystem: true, ..Opts::default() };
+        let device = |path: &Path| std::fs::metadata(path).ok().and_then(|meta| device_id(&meta));
+        if device(Path::new("/proc")).is_some_and(|x| device(&tree.0) != Some(x)) {
+            assert_eq!(tree.find_from("", &ignores, &opts), (vec!["a/b.txt".to_string()], vec!["proc".to_string()]));
+        }
+
+        // Directory that is not on the filesystem of its root, e.g. one replayed from cache.
+        let mut state = DirState::for_root(&tree.0, &ignores, &mut Vec::new());
+        state.device = device(&tree.0).map(|x| x.wrapping_add(1));
+        let mut errors = Vec::new();
+        let r: io::Result<()> = visit_dir(tree.0.join("a"), &state, &ignores, &opts, |_, _| (), |x| {
+            errors.push(tree.rel(&x.unwrap_err().path));
+            Ok(())
+        });
+        r.unwrap();
+        assert_eq!(errors, ["a"]);
+    }
+}
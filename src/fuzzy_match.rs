@@ -12,9 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp::Ordering;
+
 use fnv::FnvHashMap;
 
-type Heat = i16;
+use crate::translit::{self, Transliteration};
+
+pub type Heat = i16;
 
 pub type StrIdx = i16;
 
@@ -23,6 +27,10 @@ const INIT_SCORE: Heat = -35;
 const LEADING_PENALTY: Heat = -45;
 const WORD_START: Heat = 85;
 
+const SUBSTRING_BONUS: Heat = 100;
+const PREFIX_BONUS: Heat = 50;
+const WHOLE_WORD_BONUS: Heat = 50;
+
 #[inline]
 #[cfg(debug)]
 fn read_arr<A>(xs: &[A], idx: i16) -> &A {
@@ -69,6 +77,10 @@ pub trait Positions {
     fn infer_positions(idx: SubmatchIdx, submatches: &Vec<Submatch>) -> Self;
     fn empty() -> Self;
     fn singleton(idx: StrIdx) -> Self;
+    /// Map positions through index translation table, e.g. from transliterated string
+    /// back to the original one.
+    fn remap(self, mapping: &[StrIdx]) -> Self;
+    fn contiguous(start: StrIdx, len: StrIdx) -> Self;
 }
 
 impl Positions for Vec<StrIdx> {
@@ -89,12 +101,25 @@ impl Positions for Vec<StrIdx> {
     fn singleton(idx: SubmatchIdx) -> Self {
         vec![idx]
     }
+
+    fn remap(self, mapping: &[StrIdx]) -> Self {
+        let mut res: Vec<StrIdx> = self.into_iter().map(|i| mapping[i as usize]).collect();
+        // Several matched characters may come from the same original one.
+        res.dedup();
+        res
+    }
+
+    fn contiguous(start: StrIdx, len: StrIdx) -> Self {
+        (start..start + len).collect()
+    }
 }
 
 impl Positions for () {
     fn infer_positions(_idx: SubmatchIdx, _submatches: &Vec<Submatch>) -> Self { () }
     fn empty() -> Self { () }
     fn singleton(_idx: StrIdx) -> Self { () }
+    fn remap(self, _mapping: &[StrIdx]) -> Self {}
+    fn contiguous(_start: StrIdx, _len: StrIdx) -> Self {}
 }
 
 mod occurs {
@@ -250,11 +275,13 @@ mod occurs {
     }
 }
 
+const NO_MATCH_SCORE: Heat = 0;
+
 fn no_match<PS>() -> Match<PS>
     where
     PS: Positions,
 {
-    Match { score: 0, positions: Positions::empty() }
+    Match { score: NO_MATCH_SCORE, positions: Positions::empty() }
 }
 
 /// Return subslice that is strictly bigger that the argument index. Assumes input slice is sorted.
@@ -270,6 +297,9 @@ pub struct ReuseState {
     cache: FnvHashMap<(StrIdx, StrIdx), Option<SubmatchIdx>>,
     submatches: Vec<Submatch>,
     heatmap: Vec<Heat>,
+    translit: translit::Transliterated,
+    needle_chars: Vec<char>,
+    haystack_chars: Vec<char>,
 }
 
 impl ReuseState {
@@ -279,6 +309,9 @@ impl ReuseState {
             cache: FnvHashMap::default(),
             submatches: Vec::new(),
             heatmap: Vec::new(),
+            translit: translit::Transliterated::new(),
+            needle_chars: Vec::new(),
+            haystack_chars: Vec::new(),
         }
     }
 }
@@ -294,16 +327,213 @@ pub fn fuzzy_match<'a, 'b, 'c, 'd, PS>(
 {
     heatmap(haystack, group_seps, &mut reuse_state.heatmap);
 
-    fuzzy_match_impl(
+    let m = fuzzy_match_impl(
         &mut reuse_state.occurs,
         needle,
         haystack,
         &mut reuse_state.cache,
         &mut reuse_state.submatches,
         &reuse_state.heatmap,
+    );
+
+    add_substring_bonus(
+        m,
+        needle,
+        haystack,
+        group_seps,
+        &reuse_state.heatmap,
+        &mut reuse_state.needle_chars,
+        &mut reuse_state.haystack_chars,
     )
 }
 
+/// Like `fuzzy_match` but matches ASCII needles against romanized CJK characters of the
+/// haystack. Reported positions refer to characters of the original haystack.
+pub fn fuzzy_match_translit<PS>(
+    needle: &str,
+    haystack: &str,
+    group_seps: &[char],
+    translit: Transliteration,
+    reuse_state: &mut ReuseState,
+) -> Match<PS>
+    where
+    PS: Positions,
+{
+    if !translit.is_enabled()
+        || !needle.is_ascii()
+        || !translit::transliterate(haystack, translit, &mut reuse_state.translit)
+    {
+        return fuzzy_match(needle, haystack, group_seps, reuse_state);
+    }
+
+    let text = &reuse_state.translit.text;
+    heatmap(text, group_seps, &mut reuse_state.heatmap);
+
+    let m: Match<PS> = fuzzy_match_impl(
+        &mut reuse_state.occurs,
+        needle,
+        text,
+        &mut reuse_state.cache,
+        &mut reuse_state.submatches,
+        &reuse_state.heatmap,
+    );
+    let m = add_substring_bonus(
+        m,
+        needle,
+        text,
+        group_seps,
+        &reuse_state.heatmap,
+        &mut reuse_state.needle_chars,
+        &mut reuse_state.haystack_chars,
+    );
+
+    Match {
+        score: m.score,
+        positions: m.positions.remap(&reuse_state.translit.orig_idx),
+    }
+}
+
+/// Keys that scored candidates can be ordered by. Earlier keys take precedence.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SortKey {
+    /// Greatest score first.
+    Score,
+    /// Shortest first.
+    Length,
+    /// Order of candidates in the original collection.
+    OriginalIndex,
+    Alphabetical,
+    /// Fewest path separators first.
+    PathDepth,
+}
+
+pub const DEFAULT_SORT: &[SortKey] = &[SortKey::Score, SortKey::Length, SortKey::OriginalIndex];
+
+/// Candidate along with everything needed to order it.
+pub struct Ranked<A> {
+    pub score: i32,
+    /// Position in the original collection.
+    pub idx: usize,
+    /// Length of matched text, for multi-field candidates only weighted fields count.
+    pub len: usize,
+    pub text: String,
+    pub item: A,
+}
+
+fn path_depth(s: &str) -> usize {
+    s.chars().filter(|c| std::path::is_separator(*c)).count()
+}
+
+pub fn compare_ranked<A>(keys: &[SortKey], x: &Ranked<A>, y: &Ranked<A>) -> Ordering {
+    for key in keys {
+        let res = match key {
+            SortKey::Score => x.score.cmp(&y.score).reverse(),
+            SortKey::Length => x.len.cmp(&y.len),
+            SortKey::OriginalIndex => x.idx.cmp(&y.idx),
+            SortKey::Alphabetical => x.text.cmp(&y.text),
+            SortKey::PathDepth => path_depth(&x.text).cmp(&path_depth(&y.text)),
+        };
+        if res != Ordering::Equal {
+            return res;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Stable sort so that candidates equal under all keys keep their original order.
+pub fn sort_ranked<A>(keys: &[SortKey], xs: &mut [Ranked<A>]) {
+    xs.sort_by(|x, y| compare_ranked(keys, x, y));
+}
+
+/// Cheap check whether `fuzzy_match` would find a match, i.e. whether needle
+/// is a subsequence of haystack. Empty needle matches everything.
+pub fn is_match(needle: &str, haystack: &str) -> bool {
+    let mut hs = haystack.chars();
+    needle.chars().all(|n| hs.any(|h| chars_match(n, h)))
+}
+
+/// Weight of a single field of a multi-field candidate. Fields with zero weight are not matched.
+pub type FieldWeight = i16;
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct FieldsMatch<PS> {
+    /// Sum of per-field scores multiplied by their weights.
+    pub score: i32,
+    /// Matches of all fields that have non-zero weight, tagged by field index.
+    pub fields: Vec<(usize, Match<PS>)>,
+}
+
+/// Weight of field number `idx`. Empty weights mean that only the first field gets matched.
+pub fn field_weight(weights: &[FieldWeight], idx: usize) -> FieldWeight {
+    if weights.is_empty() {
+        if idx == 0 { 1 } else { 0 }
+    } else {
+        weights.get(idx).copied().unwrap_or(0)
+    }
+}
+
+/// Match needle against every field of a candidate that has non-zero weight and combine
+/// the results. E.g. for "path  <annotation>" candidates weights [10, 1] will match mostly
+/// against the path and use annotation only to break ties.
+pub fn fuzzy_match_fields<S, PS>(
+    needle: &str,
+    fields: &[S],
+    weights: &[FieldWeight],
+    group_seps: &[char],
+    translit: Transliteration,
+    reuse_state: &mut ReuseState,
+) -> FieldsMatch<PS>
+    where
+    S: AsRef<str>,
+    PS: Positions,
+{
+    let mut score: i32 = 0;
+    let mut matches = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let weight = field_weight(weights, i);
+        if weight == 0 {
+            continue;
+        }
+        let m: Match<PS> = fuzzy_match_translit(needle, field.as_ref(), group_seps, translit, reuse_state);
+        score += i32::from(weight) * i32::from(m.score);
+        matches.push((i, m));
+    }
+    FieldsMatch { score, fields: matches }
+}
+
+/// Score candidates, given by their fields, against `needle` and order them by `sort_keys`.
+/// This is what `score_matches` does once candidates are decoded. Only fields with non-zero
+/// weight count towards length and text that candidates are ordered by.
+pub fn rank_candidates<S, A>(
+    needle: &str,
+    candidates: Vec<(Vec<S>, A)>,
+    weights: &[FieldWeight],
+    group_seps: &[char],
+    translit: Transliteration,
+    sort_keys: &[SortKey],
+    reuse_state: &mut ReuseState,
+) -> Vec<A>
+    where
+    S: AsRef<str>,
+{
+    let mut scored = Vec::with_capacity(candidates.len());
+    for (idx, (fields, item)) in candidates.into_iter().enumerate() {
+        let m: FieldsMatch<()> = fuzzy_match_fields(needle, &fields, weights, group_seps, translit, reuse_state);
+
+        let mut weighted = fields
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| field_weight(weights, *i) != 0)
+            .map(|(_, f)| f.as_ref());
+        let text = weighted.next().unwrap_or_default().to_string();
+        let len = text.len() + weighted.map(|f| f.len()).sum::<usize>();
+
+        scored.push(Ranked { score: m.score, idx, len, text, item });
+    }
+    sort_ranked(sort_keys, &mut scored);
+    scored.into_iter().map(|r| r.item).collect()
+}
+
 fn fuzzy_match_impl<'a, 'b, 'c, 'd, 'e, 'f, PS>(
     occurs_reuse: &'a mut occurs::ReuseState,
     needle: &'b str,
@@ -363,6 +593,132 @@ where
 }
 
 
+/// Score that `top_down_match` would assign to a match of consecutive characters.
+fn contiguous_chain_score(heatmap: &[Heat], start: StrIdx, len: StrIdx) -> Heat {
+    let mut score = 0;
+    for i in start..start + len {
+        score += *read_arr(heatmap, i);
+    }
+    // Contiguous count grows from the end of the chain.
+    for k in 0..(len - 1).max(0) {
+        score += contiguous_bonus(true, k);
+    }
+    score
+}
+
+fn chars_match(needle_char: char, haystack_char: char) -> bool {
+    needle_char == haystack_char
+        || is_capital(haystack_char) && {
+            let mut lower = haystack_char.to_lowercase();
+            lower.len() == 1 && lower.next() == Some(needle_char)
+        }
+}
+
+fn is_word_start(prev: Option<char>, c: char) -> bool {
+    match prev {
+        None => true,
+        Some(p) => !is_word(p) && is_word(c) || !p.is_uppercase() && c.is_uppercase(),
+    }
+}
+
+/// Bonus for an occurrence of the whole needle at `start`. Occurrences within base
+/// name, i.e. the last group, are worth twice as much as the ones in directories.
+fn substring_bonus(
+    needle_len: usize,
+    start: usize,
+    haystack: &[char],
+    group_seps: &[char],
+) -> Heat {
+    let end = start + needle_len;
+
+    let group_start = haystack[..start]
+        .iter()
+        .rposition(|c| is_member(*c, group_seps))
+        .map_or(0, |i| i + 1);
+    let is_base_group = !haystack[end..].iter().any(|c| is_member(*c, group_seps));
+
+    let prev = if start == 0 { None } else { Some(haystack[start - 1]) };
+    let next = haystack.get(end).copied();
+
+    let mut bonus = SUBSTRING_BONUS;
+    if start == group_start {
+        bonus += PREFIX_BONUS;
+    }
+    let starts_word = is_word_start(prev, haystack[start]);
+    let ends_word = match next {
+        None => true,
+        Some(n) => is_word_start(Some(haystack[end - 1]), n) || !is_word(n),
+    };
+    if starts_word && ends_word {
+        bonus += WHOLE_WORD_BONUS;
+    }
+
+    if is_base_group {
+        bonus
+    } else {
+        bonus / 2
+    }
+}
+
+/// If needle occurs in haystack as a contiguous substring then make the match
+/// point to the best such occurrence and add bonus for it.
+fn add_substring_bonus<PS>(
+    m: Match<PS>,
+    needle: &str,
+    haystack: &str,
+    group_seps: &[char],
+    heatmap: &[Heat],
+    needle_chars: &mut Vec<char>,
+    haystack_chars: &mut Vec<char>,
+) -> Match<PS>
+    where
+    PS: Positions,
+{
+    // Substring occurrence is a match too, so there’s nothing to look for if `m` isn’t one.
+    // Zero is a valid score as well though, hence the cheap check.
+    if needle.is_empty() || m.score == NO_MATCH_SCORE && !is_match(needle, haystack) {
+        return m;
+    }
+
+    needle_chars.clear();
+    needle_chars.extend(needle.chars());
+    haystack_chars.clear();
+    haystack_chars.extend(haystack.chars());
+
+    let n = needle_chars.len();
+    if n > haystack_chars.len() {
+        return m;
+    }
+
+    let mut best: Option<(StrIdx, Heat, Heat)> = None;
+    for start in 0..=haystack_chars.len() - n {
+        let is_occurrence = needle_chars
+            .iter()
+            .zip(&haystack_chars[start..start + n])
+            .all(|(x, y)| chars_match(*x, *y));
+        if !is_occurrence {
+            continue;
+        }
+        let chain = contiguous_chain_score(heatmap, start as StrIdx, n as StrIdx);
+        let bonus = substring_bonus(n, start, haystack_chars, group_seps);
+        let is_better = match best {
+            None => true,
+            Some((_, best_chain, best_bonus)) => chain + bonus > best_chain + best_bonus,
+        };
+        if is_better {
+            best = Some((start as StrIdx, chain, bonus));
+        }
+    }
+
+    match best {
+        None => m,
+        Some((start, chain, bonus)) => Match {
+            score: m.score.max(chain) + bonus,
+            positions: Positions::contiguous(start, n as StrIdx),
+        },
+    }
+}
+
 type SubmatchIdx = i16;
 
 fn is_score_better(new: i16, old: i16) -> bool {
@@ -1118,8 +1474,251 @@ mod test {
             &[],
             &mut reuse,
         );
-        assert_eq!(m1, Match { score: 214, positions: vec![0, 1, 2] });
-        assert_eq!(m2, Match { score: 142, positions: vec![0, 1] });
-        assert_eq!(m3, Match { score: 126, positions: vec![1, 2, 3] });
+        // Substring bonus plus prefix bonus.
+        assert_eq!(m1, Match { score: 214 + 150, positions: vec![0, 1, 2] });
+        assert_eq!(m2, Match { score: 142 + 150, positions: vec![0, 1] });
+        // Substring bonus only.
+        assert_eq!(m3, Match { score: 126 + 100, positions: vec![1, 2, 3] });
+    }
+
+    #[test]
+    fn fuzzy_match_fields_default_weights() {
+        let mut reuse = ReuseState::new();
+        let m: FieldsMatch<Vec<StrIdx>> = fuzzy_match_fields(
+            "foo",
+            &["foobar", "foo"],
+            &[],
+            &[],
+            Transliteration::none(),
+            &mut reuse,
+        );
+        assert_eq!(m, FieldsMatch {
+            score: 364,
+            fields: vec![(0, Match { score: 364, positions: vec![0, 1, 2] })],
+        });
+    }
+
+    #[test]
+    fn fuzzy_match_fields_weights() {
+        let mut reuse = ReuseState::new();
+        let m: FieldsMatch<Vec<StrIdx>> = fuzzy_match_fields(
+            "fo",
+            &["foobar", "xyz", "foobar"],
+            &[2, 0, 1],
+            &[],
+            Transliteration::none(),
+            &mut reuse,
+        );
+        assert_eq!(m, FieldsMatch {
+            score: 3 * 292,
+            fields: vec![
+                (0, Match { score: 292, positions: vec![0, 1] }),
+                (2, Match { score: 292, positions: vec![0, 1] }),
+            ],
+        });
+    }
+
+    #[test]
+    fn fuzzy_match_fields_tie_break() {
+        let mut reuse = ReuseState::new();
+        let m1: FieldsMatch<()> = fuzzy_match_fields("foo", &["foo.hs", "bar"], &[10, 1], &[], Transliteration::none(), &mut reuse);
+        let m2: FieldsMatch<()> = fuzzy_match_fields("foo", &["foo.hs", "foo"], &[10, 1], &[], Transliteration::none(), &mut reuse);
+        assert!(m2.score > m1.score);
+    }
+
+    #[test]
+    fn fuzzy_match_translit_pinyin_initials() {
+        let mut reuse = ReuseState::new();
+        let m: Match<Vec<StrIdx>> = fuzzy_match_translit(
+            "bj",
+            "\u{5317}\u{4EAC}.txt",
+            &[],
+            Transliteration { han: true, kana: false },
+            &mut reuse,
+        );
+        assert_eq!(m.positions, vec![0, 1]);
+        assert!(m.score > 0);
+    }
+
+    #[test]
+    fn fuzzy_match_translit_pinyin_syllables() {
+        let mut reuse = ReuseState::new();
+        let m: Match<Vec<StrIdx>> = fuzzy_match_translit(
+            "beijt",
+            "src/\u{5317}\u{4EAC}.txt",
+            &[],
+            Transliteration::all(),
+            &mut reuse,
+        );
+        assert_eq!(m.positions, vec![4, 5, 7]);
+    }
+
+    #[test]
+    fn fuzzy_match_translit_kana() {
+        let mut reuse = ReuseState::new();
+        let m: Match<Vec<StrIdx>> = fuzzy_match_translit(
+            "kn",
+            "\u{304B}\u{306A}",
+            &[],
+            Transliteration { han: false, kana: true },
+            &mut reuse,
+        );
+        assert_eq!(m.positions, vec![0, 1]);
+
+        let m2: Match<Vec<StrIdx>> = fuzzy_match_translit(
+            "kn",
+            "\u{304B}\u{306A}",
+            &[],
+            Transliteration { han: true, kana: false },
+            &mut reuse,
+        );
+        assert_eq!(m2, no_match());
+    }
+
+    #[test]
+    fn fuzzy_match_translit_disabled_is_plain_match() {
+        let mut reuse = ReuseState::new();
+        let m: Match<Vec<StrIdx>> = fuzzy_match_translit("foo", "foobar", &[], Transliteration::all(), &mut reuse);
+        assert_eq!(m, Match { score: 364, positions: vec![0, 1, 2] });
+    }
+
+    fn ranked<'a>(score: i32, idx: usize, text: &'a str) -> Ranked<&'a str> {
+        Ranked { score, idx, len: text.len(), text: text.to_string(), item: text }
+    }
+
+    /// Order string candidates the way `score_matches` does by default.
+    fn rank<'a>(needle: &str, candidates: &[&'a str], group_seps: &[char]) -> Vec<&'a str> {
+        let mut reuse = ReuseState::new();
+        rank_candidates(
+            needle,
+            candidates.iter().map(|c| (vec![*c], *c)).collect(),
+            &[],
+            group_seps,
+            Transliteration::none(),
+            DEFAULT_SORT,
+            &mut reuse,
+        )
+    }
+
+    #[test]
+    fn substring_bonus_positions() {
+        let mut reuse = ReuseState::new();
+        let m: Match<Vec<StrIdx>> = fuzzy_match("bar", "b/a/r/xbarx", &['/'], &mut reuse);
+        assert_eq!(m.positions, vec![7, 8, 9]);
+    }
+
+    #[test]
+    fn substring_outranks_scattered() {
+        assert_eq!(
+            rank("foo", &["f/o/o.rs", "src/xfoox.rs"], &['/']),
+            vec!["src/xfoox.rs", "f/o/o.rs"]
+        );
+    }
+
+    #[test]
+    fn prefix_outranks_substring() {
+        assert_eq!(
+            rank("foo", &["src/xfoox.rs", "src/foox.rs"], &['/']),
+            vec!["src/foox.rs", "src/xfoox.rs"]
+        );
+    }
+
+    #[test]
+    fn whole_word_outranks_prefix() {
+        assert_eq!(
+            rank("foo", &["src/foobar.rs", "src/foo.rs", "src/foo_bar.rs"], &['/']),
+            vec!["src/foo.rs", "src/foo_bar.rs", "src/foobar.rs"]
+        );
+    }
+
+    #[test]
+    fn base_name_outranks_directory() {
+        assert_eq!(
+            rank("foo", &["foo/bar.rs", "bar/foo.rs"], &['/']),
+            vec!["bar/foo.rs", "foo/bar.rs"]
+        );
+    }
+
+    #[test]
+    fn rank_candidates_weighted_fields() {
+        let mut reuse = ReuseState::new();
+        let candidates = vec![
+            (vec!["bar.rs", "foo"], 0),
+            (vec!["foo.rs", "bar"], 1),
+            (vec!["xfoo.rs", "foo"], 2),
+        ];
+        // Annotation only breaks the tie between paths that match equally well.
+        assert_eq!(
+            rank_candidates("foo", candidates.clone(), &[10, 1], &[], Transliteration::none(), DEFAULT_SORT, &mut reuse),
+            vec![1, 2, 0]
+        );
+        assert_eq!(
+            rank_candidates("foo", candidates, &[0, 1], &[], Transliteration::none(), DEFAULT_SORT, &mut reuse),
+            vec![0, 2, 1]
+        );
+    }
+
+    #[test]
+    fn rank_candidates_sort_keys() {
+        let mut reuse = ReuseState::new();
+        let candidates = vec![(vec!["b/foo"], 0), (vec!["foo"], 1), (vec!["a/b/foo"], 2)];
+        assert_eq!(
+            rank_candidates("foo", candidates.clone(), &[], &['/'], Transliteration::none(), &[SortKey::OriginalIndex], &mut reuse),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            rank_candidates("foo", candidates, &[], &['/'], Transliteration::none(), &[SortKey::Alphabetical], &mut reuse),
+            vec![2, 0, 1]
+        );
+    }
+
+    #[test]
+    fn no_match_has_no_substring_bonus() {
+        let mut reuse = ReuseState::new();
+        let m: Match<Vec<StrIdx>> = fuzzy_match("oof", "foo", &[], &mut reuse);
+        assert_eq!(m, no_match());
+    }
+
+    #[test]
+    fn is_match_subsequence() {
+        assert!(is_match("fb", "FooBar"));
+        assert!(is_match("", "foo"));
+        assert!(!is_match("bf", "foobar"));
+        assert!(!is_match("F", "foo"));
+    }
+
+    fn sorted<'a>(keys: &[SortKey], mut xs: Vec<Ranked<&'a str>>) -> Vec<&'a str> {
+        sort_ranked(keys, &mut xs);
+        xs.into_iter().map(|r| r.item).collect()
+    }
+
+    #[test]
+    fn sort_default_is_stable() {
+        let xs = vec![ranked(1, 0, "bb"), ranked(2, 1, "a"), ranked(1, 2, "aa"), ranked(1, 3, "c")];
+        assert_eq!(sorted(DEFAULT_SORT, xs), vec!["a", "c", "bb", "aa"]);
+    }
+
+    #[test]
+    fn sort_alphabetical_tie_break() {
+        let xs = vec![ranked(1, 0, "bb"), ranked(2, 1, "a"), ranked(1, 2, "aa"), ranked(1, 3, "c")];
+        assert_eq!(
+            sorted(&[SortKey::Score, SortKey::Alphabetical], xs),
+            vec!["a", "aa", "bb", "c"]
+        );
+    }
+
+    #[test]
+    fn sort_path_depth() {
+        let xs = vec![ranked(1, 0, "a/b/c"), ranked(1, 1, "a/bcdef"), ranked(1, 2, "abc")];
+        assert_eq!(
+            sorted(&[SortKey::PathDepth, SortKey::OriginalIndex], xs),
+            vec!["abc", "a/bcdef", "a/b/c"]
+        );
+    }
+
+    #[test]
+    fn sort_original_index_only() {
+        let xs = vec![ranked(1, 2, "x"), ranked(5, 0, "y"), ranked(3, 1, "z")];
+        assert_eq!(sorted(&[SortKey::OriginalIndex], xs), vec!["y", "z", "x"]);
     }
 }
@@ -0,0 +1,453 @@
+// Copyright 2021 Sergey Vinokurov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reader of tracked paths from git index, see `Documentation/gitformat-index.txt` in git
+//! sources. Supports versions 2 through 4 with SHA-1 or SHA-256 object ids.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+const SIGNATURE: &[u8] = b"DIRC";
+
+/// Size of stat data that precedes object id of every entry.
+const STAT_DATA_SIZE: usize = 40;
+
+/// Size of object ids and checksums in bytes.
+const SHA1_LEN: usize = 20;
+const SHA256_LEN: usize = 32;
+
+const FLAG_EXTENDED: u16 = 0x4000;
+const FLAG_STAGE_MASK: u16 = 0x3000;
+const FLAG_NAME_MASK: u16 = 0x0fff;
+const EXT_FLAG_SKIP_WORKTREE: u16 = 0x4000;
+
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_REGULAR: u32 = 0o100000;
+const MODE_SYMLINK: u32 = 0o120000;
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Directory holding repository data of the work tree at `root`. Follows `.git` files
+/// that linked work trees and submodules use.
+pub fn git_dir(root: &Path) -> io::Result<PathBuf> {
+    let dot_git = root.join(".git");
+    if std::fs::metadata(&dot_git)?.is_dir() {
+        return Ok(dot_git);
+    }
+    let contents = std::fs::read_to_string(&dot_git)?;
+    let dir = contents
+        .lines()
+        .find_map(|l| l.strip_prefix("gitdir:"))
+        .ok_or_else(|| invalid(format!("{}: expected 'gitdir: <path>'", dot_git.display())))?;
+    Ok(root.join(dir.trim()))
+}
+
+/// Paths of files tracked in the work tree at `root` relative to it. Files excluded by
+/// sparse checkout and submodules are left out, paths with merge conflicts are reported once.
+pub fn tracked_files(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let git_dir = git_dir(root)?;
+    let index = git_dir.join("index");
+    let bytes = match std::fs::read(&index) {
+        Ok(x) => x,
+        // Fresh repository without anything staged.
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let hash_len = hash_len(&git_dir)?;
+    parse(&bytes, hash_len).map_err(|err| io::Error::new(err.kind(), format!("{}: {}", index.display(), err)))
+}
+
+/// Length of object ids in the repository at `git_dir`, which depends on its
+/// `extensions.objectFormat` setting.
+fn hash_len(git_dir: &Path) -> io::Result<usize> {
+    // Linked work trees share configuration with the main one.
+    let common_dir = match std::fs::read_to_string(git_dir.join("commondir")) {
+        Ok(x) => git_dir.join(x.trim()),
+        Err(_) => git_dir.to_path_buf(),
+    };
+    let config = match std::fs::read_to_string(common_dir.join("config")) {
+        Ok(x) => x,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    match object_format(&config).as_deref() {
+        None | Some("sha1") => Ok(SHA1_LEN),
+        Some("sha256") => Ok(SHA256_LEN),
+        Some(x) => Err(invalid(format!("Unsupported object format {}", x))),
+    }
+}
+
+/// Value of `extensions.objectFormat` in git config `contents`, lowercased.
+fn object_format(contents: &str) -> Option<String> {
+    let mut in_extensions = false;
+    let mut res = None;
+    for line in contents.lines() {
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix('[') {
+            let section = header.split(']').next().unwrap_or_default();
+            in_extensions = section.trim().eq_ignore_ascii_case("extensions");
+        } else if in_extensions {
+            let (key, value) = match line.split_once('=') {
+                Some(x) => x,
+                None => continue,
+            };
+            if key.trim().eq_ignore_ascii_case("objectformat") {
+                let value = value.split(['#', ';']).next().unwrap_or_default();
+                // Later settings take precedence.
+                res = Some(value.trim().trim_matches('"').to_ascii_lowercase());
+            }
+        }
+    }
+    res
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|&e| e <= self.bytes.len()).ok_or_else(|| invalid("Truncated index"))?;
+        let res = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(res)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let x = self.take(2)?;
+        Ok(u16::from_be_bytes([x[0], x[1]]))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let x = self.take(4)?;
+        Ok(u32::from_be_bytes([x[0], x[1], x[2], x[3]]))
+    }
+
+    /// Bytes up to the next NUL, which is consumed as well.
+    fn until_nul(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.bytes[self.pos..]
+            .iter()
+            .position(|&c| c == 0)
+            .ok_or_else(|| invalid("Unterminated path"))?;
+        let res = self.take(len)?;
+        self.pos += 1;
+        Ok(res)
+    }
+
+    /// Variable-length integer used by version 4 for prefix compression.
+    fn varint(&mut self) -> io::Result<usize> {
+        let mut c = self.take(1)?[0];
+        let mut res = usize::from(c & 0x7f);
+        while c & 0x80 != 0 {
+            c = self.take(1)?[0];
+            res = res
+                .checked_add(1)
+                .and_then(|x| x.checked_mul(128))
+                .ok_or_else(|| invalid("Prefix length overflow"))?
+                | usize::from(c & 0x7f);
+        }
+        Ok(res)
+    }
+}
+
+/// Paths in index `bytes` of a repository whose object ids are `hash_len` bytes long.
+fn parse(bytes: &[u8], hash_len: usize) -> io::Result<Vec<PathBuf>> {
+    let mut r = Reader { bytes, pos: 0 };
+    if r.take(4)? != SIGNATURE {
+        return Err(invalid("Not a git index"));
+    }
+    let version = r.u32()?;
+    if !(2..=4).contains(&version) {
+        return Err(invalid(format!("Unsupported index version {}", version)));
+    }
+    let count = r.u32()?;
+
+    let mut res: Vec<PathBuf> = Vec::new();
+    let mut path: Vec<u8> = Vec::new();
+    for _ in 0..count {
+        let start = r.pos;
+        let fixed = r.take(STAT_DATA_SIZE + hash_len)?;
+        let mode = u32::from_be_bytes([fixed[24], fixed[25], fixed[26], fixed[27]]);
+        let flags = r.u16()?;
+        let ext_flags = if version >= 3 && flags & FLAG_EXTENDED != 0 { r.u16()? } else { 0 };
+
+        if version == 4 {
+            let strip = r.varint()?;
+            let keep = path.len().checked_sub(strip).ok_or_else(|| invalid("Invalid path prefix"))?;
+            path.truncate(keep);
+            path.extend_from_slice(r.until_nul()?);
+        } else {
+            let name_len = usize::from(flags & FLAG_NAME_MASK);
+            path.clear();
+            path.extend_from_slice(if name_len < usize::from(FLAG_NAME_MASK) {
+                let name = r.take(name_len)?;
+                r.take(1)?;
+                name
+            } else {
+                r.until_nul()?
+            });
+            // Entries are padded with NULs to a multiple of 8 bytes, the terminating NUL included.
+            let len = r.pos - start;
+            r.take((8 - len % 8) % 8)?;
+        }
+
+        let is_file = matches!(mode & MODE_TYPE_MASK, MODE_REGULAR | MODE_SYMLINK);
+        if !is_file || ext_flags & EXT_FLAG_SKIP_WORKTREE != 0 {
+            continue;
+        }
+        // Conflicting stages of the same path are adjacent.
+        if flags & FLAG_STAGE_MASK != 0 && res.last().is_some_and(|p| path_bytes_eq(p, &path)) {
+            continue;
+        }
+        res.push(path_from_bytes(&path)?);
+    }
+
+    // Split index keeps part of entries in a separate shared file.
+    while r.bytes.len() - r.pos > hash_len {
+        let sig = r.take(4)?;
+        let size = r.u32()? as usize;
+        if sig == b"link" {
+            return Err(invalid("Split index is not supported"));
+        }
+        r.take(size)?;
+    }
+
+    Ok(res)
+}
+
+#[cfg(target_family = "unix")]
+fn path_from_bytes(bytes: &[u8]) -> io::Result<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+}
+
+#[cfg(target_family = "windows")]
+fn path_from_bytes(bytes: &[u8]) -> io::Result<PathBuf> {
+    std::str::from_utf8(bytes)
+        .map(PathBuf::from)
+        .map_err(|_| invalid(format!("Non-unicode path: {}", String::from_utf8_lossy(bytes))))
+}
+
+#[cfg(target_family = "unix")]
+fn path_bytes_eq(p: &Path, bytes: &[u8]) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    p.as_os_str().as_bytes() == bytes
+}
+
+#[cfg(target_family = "windows")]
+fn path_bytes_eq(p: &Path, bytes: &[u8]) -> bool {
+    p.to_str().is_some_and(|x| x.as_bytes() == bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MODE_GITLINK: u32 = 0o160000;
+
+    struct Index {
+        version: u32,
+        hash_len: usize,
+        count: u32,
+        entries: Vec<u8>,
+    }
+
+    impl Index {
+        fn new(version: u32) -> Self {
+            Index { version, hash_len: SHA1_LEN, count: 0, entries: Vec::new() }
+        }
+
+        /// Add entry with path `name`. For version 4 `name` is what remains after stripping
+        /// `strip` bytes off the previous path.
+        fn entry(mut self, mode: u32, stage: u16, ext_flags: Option<u16>, strip: usize, name: &str) -> Self {
+            let out = &mut self.entries;
+            let start = out.len();
+            out.extend_from_slice(&[0; 24]);
+            out.extend_from_slice(&mode.to_be_bytes());
+            out.extend_from_slice(&[0; 12]);
+            out.extend_from_slice(&vec![0; self.hash_len]);
+            let mut flags = stage << 12 | (name.len() as u16).min(FLAG_NAME_MASK);
+            if ext_flags.is_some() {
+                flags |= FLAG_EXTENDED;
+            }
+            out.extend_from_slice(&flags.to_be_bytes());
+            if let Some(x) = ext_flags {
+                out.extend_from_slice(&x.to_be_bytes());
+            }
+            if self.version == 4 {
+                assert!(strip < 0x80);
+                out.push(strip as u8);
+                out.extend_from_slice(name.as_bytes());
+                out.push(0);
+            } else {
+                out.extend_from_slice(name.as_bytes());
+                out.push(0);
+                while (out.len() - start) % 8 != 0 {
+                    out.push(0);
+                }
+            }
+            self.count += 1;
+            self
+        }
+
+        fn file(self, name: &str) -> Self {
+            self.entry(MODE_REGULAR | 0o644, 0, None, 0, name)
+        }
+
+        fn bytes(&self) -> Vec<u8> {
+            let mut res = SIGNATURE.to_vec();
+            res.extend_from_slice(&self.version.to_be_bytes());
+            res.extend_from_slice(&self.count.to_be_bytes());
+            res.extend_from_slice(&self.entries);
+            // Checksum.
+            res.extend_from_slice(&vec![0; self.hash_len]);
+            res
+        }
+    }
+
+    fn paths(xs: &[&str]) -> Vec<PathBuf> {
+        xs.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn version_2() {
+        let index = Index::new(2)
+            .file("Cargo.toml")
+            .entry(MODE_SYMLINK, 0, None, 0, "link")
+            .entry(MODE_GITLINK, 0, None, 0, "submodule")
+            .file("src/lib.rs");
+        assert_eq!(parse(&index.bytes(), SHA1_LEN).unwrap(), paths(&["Cargo.toml", "link", "src/lib.rs"]));
+    }
+
+    #[test]
+    fn version_3_extended_flags() {
+        let index = Index::new(3)
+            .file("a")
+            .entry(MODE_REGULAR | 0o644, 0, Some(EXT_FLAG_SKIP_WORKTREE), 0, "sparse/b")
+            // Intent-to-add entry, extended but still in work tree.
+            .entry(MODE_REGULAR | 0o644, 0, Some(0x2000), 0, "c")
+            .file("d");
+        assert_eq!(parse(&index.bytes(), SHA1_LEN).unwrap(), paths(&["a", "c", "d"]));
+    }
+
+    #[test]
+    fn version_4_prefix_compression() {
+        let index = Index::new(4)
+            .entry(MODE_REGULAR | 0o644, 0, None, 0, "src/find.rs")
+            .entry(MODE_REGULAR | 0o644, 0, None, 7, "lib.rs")
+            .entry(MODE_REGULAR | 0o644, 0, Some(EXT_FLAG_SKIP_WORKTREE), 6, "path.rs")
+            .entry(MODE_REGULAR | 0o644, 0, None, 11, "tests/a.rs");
+        assert_eq!(parse(&index.bytes(), SHA1_LEN).unwrap(), paths(&["src/find.rs", "src/lib.rs", "tests/a.rs"]));
+    }
+
+    #[test]
+    fn version_4_invalid_prefix() {
+        let index = Index::new(4).file("a").entry(MODE_REGULAR | 0o644, 0, None, 2, "b");
+        assert!(parse(&index.bytes(), SHA1_LEN).is_err());
+    }
+
+    #[test]
+    fn conflict_stages_reported_once() {
+        let index = Index::new(2)
+            .entry(MODE_REGULAR | 0o644, 1, None, 0, "conflict")
+            .entry(MODE_REGULAR | 0o644, 2, None, 0, "conflict")
+            .entry(MODE_REGULAR | 0o644, 3, None, 0, "conflict")
+            .file("other");
+        assert_eq!(parse(&index.bytes(), SHA1_LEN).unwrap(), paths(&["conflict", "other"]));
+    }
+
+    #[test]
+    fn long_name() {
+        let name = "x".repeat(5000);
+        let index = Index::new(2).file(&name).file("y");
+        assert_eq!(parse(&index.bytes(), SHA1_LEN).unwrap(), paths(&[&name, "y"]));
+    }
+
+    #[test]
+    fn extensions_are_skipped() {
+        let mut bytes = Index::new(2).file("a").bytes();
+        let checksum = bytes.split_off(bytes.len() - 20);
+        bytes.extend_from_slice(b"TREE");
+        bytes.extend_from_slice(&3u32.to_be_bytes());
+        bytes.extend_from_slice(b"xyz");
+        bytes.extend_from_slice(&checksum);
+        assert_eq!(parse(&bytes, SHA1_LEN).unwrap(), paths(&["a"]));
+    }
+
+    #[test]
+    fn split_index_is_rejected() {
+        let mut bytes = Index::new(2).file("a").bytes();
+        let checksum = bytes.split_off(bytes.len() - 20);
+        bytes.extend_from_slice(b"link");
+        bytes.extend_from_slice(&0u32.to_be_bytes());
+        bytes.extend_from_slice(&checksum);
+        assert!(parse(&bytes, SHA1_LEN).is_err());
+    }
+
+    #[test]
+    fn truncated_input() {
+        for version in [2, 3, 4] {
+            let bytes = Index::new(version).file("src/lib.rs").file("src/path.rs").bytes();
+            let entries_end = bytes.len() - 20;
+            for len in 0..entries_end {
+                assert!(parse(&bytes[..len], SHA1_LEN).is_err(), "version {}, length {}", version, len);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_header() {
+        assert!(parse(b"DIRX\0\0\0\x02\0\0\0\0", SHA1_LEN).is_err());
+        assert!(parse(b"DIRC\0\0\0\x05\0\0\0\0", SHA1_LEN).is_err());
+    }
+
+    #[test]
+    fn sha256_object_ids() {
+        let index = Index { hash_len: SHA256_LEN, ..Index::new(2) }.file("a").file("src/b.rs");
+        assert_eq!(parse(&index.bytes(), SHA256_LEN).unwrap(), paths(&["a", "src/b.rs"]));
+        let index = Index { hash_len: SHA256_LEN, ..Index::new(4) }.file("a").entry(MODE_REGULAR | 0o644, 0, None, 1, "b");
+        assert_eq!(parse(&index.bytes(), SHA256_LEN).unwrap(), paths(&["a", "b"]));
+    }
+
+    #[test]
+    fn object_format_from_config() {
+        assert_eq!(object_format("[core]\n\tbare = false\n"), None);
+        assert_eq!(object_format("[core]\n\tobjectformat = sha256\n"), None);
+        assert_eq!(
+            object_format("[core]\n\trepositoryformatversion = 1\n[extensions]\n\tobjectFormat = SHA256 ; comment\n").as_deref(),
+            Some("sha256")
+        );
+        assert_eq!(object_format("[Extensions]\nobjectformat=\"sha1\"\n").as_deref(), Some("sha1"));
+    }
+
+    #[test]
+    fn object_format_of_repository() {
+        let dir = std::env::temp_dir().join(format!("git-index-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("worktrees/wt")).unwrap();
+        let hash_len_with = |config: &str| {
+            std::fs::write(dir.join("config"), config).unwrap();
+            hash_len(&dir)
+        };
+        assert_eq!(hash_len_with("").unwrap(), SHA1_LEN);
+        assert_eq!(hash_len_with("[extensions]\n\tobjectFormat = sha256\n").unwrap(), SHA256_LEN);
+        // Linked work tree reads configuration of the main one.
+        std::fs::write(dir.join("worktrees/wt/commondir"), "../..\n").unwrap();
+        assert_eq!(hash_len(&dir.join("worktrees/wt")).unwrap(), SHA256_LEN);
+        assert!(hash_len_with("[extensions]\n\tobjectFormat = md5\n").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,456 @@
+// Copyright 2021 Sergey Vinokurov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::Hash;
+use std::io;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::find::{self, DirState, EntryError, Ignores, Opts};
+
+/// How long the watcher sleeps when there are no events before checking whether it should stop.
+const IDLE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+struct Contents {
+    files: BTreeSet<PathBuf>,
+    errors: Vec<EntryError>,
+    /// Whether roots are being scanned from scratch, so that `files` are not complete yet.
+    scanning: bool,
+}
+
+/// Files under a set of roots, found the same way `find::find_rec` would, that are kept up
+/// to date by a background thread watching for filesystem changes with inotify. Roots are
+/// scanned on that thread too, files found so far are available while it goes.
+///
+/// A directory reachable through several symlinks is tracked only under the path it was
+/// found through first. Changes to `.gitignore` files and to metadata checked by filters
+/// are not tracked.
+pub struct ProjectIndex {
+    roots: Vec<PathBuf>,
+    contents: Arc<Mutex<Contents>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl ProjectIndex {
+    pub fn new(roots: Vec<PathBuf>, ignores: Arc<Ignores>, opts: Opts) -> io::Result<Self> {
+        let contents = Arc::new(Mutex::new(Contents { scanning: true, ..Contents::default() }));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let watcher = Watcher::new(roots.clone(), ignores, opts, Inotify::init()?, contents.clone(), stop.clone());
+        std::thread::Builder::new()
+            .name("project-index".to_string())
+            .spawn(move || watcher.run())?;
+
+        Ok(ProjectIndex { roots, contents, stop })
+    }
+
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Call `f` with current set of files and errors encountered so far.
+    pub fn with_contents<A>(&self, f: impl FnOnce(&BTreeSet<PathBuf>, &[EntryError]) -> A) -> A {
+        let c = self.contents.lock().unwrap();
+        f(&c.files, &c.errors)
+    }
+
+    /// Whether roots are still being scanned so that some files may be missing.
+    pub fn is_scanning(&self) -> bool {
+        self.contents.lock().unwrap().scanning
+    }
+}
+
+impl Drop for ProjectIndex {
+    fn drop(&mut self) {
+        // Don’t wait for the watcher, it will notice within IDLE_INTERVAL.
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Paths within `parent` in the order of `BTreeSet` and `BTreeMap`, which compare paths
+/// component-wise so that a subtree always occupies a contiguous range.
+fn subtree<'a, A: 'a>(
+    iter: impl Iterator<Item = A> + 'a,
+    parent: &'a Path,
+    key: impl Fn(&A) -> &Path + 'a,
+) -> impl Iterator<Item = A> + 'a {
+    iter.take_while(move |x| key(x).starts_with(parent))
+}
+
+/// Directory watches, so that watcher logic can be exercised without inotify.
+trait Watches {
+    type Wd: Clone + Eq + Hash;
+
+    /// Watch `dir`, adding it twice under different paths yields the same descriptor.
+    fn add(&mut self, dir: &Path) -> io::Result<Self::Wd>;
+
+    fn remove(&mut self, wd: Self::Wd);
+}
+
+impl Watches for Inotify {
+    type Wd = WatchDescriptor;
+
+    fn add(&mut self, dir: &Path) -> io::Result<WatchDescriptor> {
+        self.watches().add(
+            dir,
+            WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVED_FROM | WatchMask::MOVED_TO | WatchMask::ONLYDIR,
+        )
+    }
+
+    fn remove(&mut self, wd: WatchDescriptor) {
+        // Fails if directory was deleted and the watch is already gone.
+        let _ = self.watches().remove(wd);
+    }
+}
+
+struct Watcher<W: Watches> {
+    roots: Vec<PathBuf>,
+    ignores: Arc<Ignores>,
+    opts: Opts,
+    watches: W,
+    dirs: BTreeMap<PathBuf, W::Wd>,
+    wds: HashMap<W::Wd, PathBuf>,
+    contents: Arc<Mutex<Contents>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl<W: Watches> Watcher<W> {
+    fn new(
+        roots: Vec<PathBuf>,
+        ignores: Arc<Ignores>,
+        mut opts: Opts,
+        watches: W,
+        contents: Arc<Mutex<Contents>>,
+        stop: Arc<AtomicBool>,
+    ) -> Self {
+        // Directories are tracked through watches.
+        opts.mode = find::Mode::Files;
+        Watcher { roots, ignores, opts, watches, dirs: BTreeMap::new(), wds: HashMap::new(), contents, stop }
+    }
+
+    fn scan_roots(&mut self) {
+        for root in self.roots.clone() {
+            if self.ignores.ignored_dirs.is_path_match(&root) {
+                continue;
+            }
+            let mut errors = Vec::new();
+            let state = DirState::for_root(&root, &self.ignores, &mut errors);
+            self.contents.lock().unwrap().errors.extend(errors);
+            self.scan(root, state);
+        }
+    }
+
+    /// Add files under `dir` to contents and start watching it along with all its
+    /// subdirectories that are not watched yet. Stops early if the index is dropped.
+    fn scan(&mut self, dir: PathBuf, state: DirState) {
+        let mut queue = vec![(dir, state)];
+        while let Some((dir, state)) = queue.pop() {
+            if self.stop.load(Ordering::Relaxed) {
+                return;
+            }
+            let mut files = Vec::new();
+            let mut errors = Vec::new();
+            match self.watch(&dir) {
+                Ok(true) => (),
+                // Already tracked under another path, e.g. reached through a symlink cycle.
+                Ok(false) => continue,
+                Err(err) => errors.push(EntryError { path: dir.clone(), err }),
+            }
+            let dirs = &self.dirs;
+            let res: io::Result<()> = find::visit_dir(
+                dir,
+                &state,
+                &self.ignores,
+                &self.opts,
+                |p, child_state| {
+                    if !dirs.contains_key(&p) {
+                        queue.push((p, child_state));
+                    }
+                },
+                |x| {
+                    match x {
+                        Ok((p, _kind)) => files.push(p),
+                        Err(err) => errors.push(err),
+                    }
+                    Ok(())
+                },
+            );
+            // Only fails if recording does.
+            debug_assert!(res.is_ok());
+            let mut c = self.contents.lock().unwrap();
+            c.files.extend(files);
+            c.errors.append(&mut errors);
+        }
+    }
+
+    /// Start watching `dir` unless it is watched already. Returns false if the same
+    /// directory is watched under a different path.
+    fn watch(&mut self, dir: &Path) -> io::Result<bool> {
+        if self.dirs.contains_key(dir) {
+            return Ok(true);
+        }
+        let wd = self.watches.add(dir)?;
+        if self.wds.contains_key(&wd) {
+            return Ok(false);
+        }
+        self.wds.insert(wd.clone(), dir.to_owned());
+        self.dirs.insert(dir.to_owned(), wd);
+        Ok(true)
+    }
+
+    /// Drop `path` along with everything under it.
+    fn forget(&mut self, path: &Path) {
+        {
+            let mut c = self.contents.lock().unwrap();
+            let gone: Vec<PathBuf> = subtree(
+                c.files.range::<Path, _>((Bound::Included(path), Bound::Unbounded)),
+                path,
+                |p| p.as_path(),
+            ).cloned().collect();
+            for p in gone {
+                c.files.remove(&p);
+            }
+            c.errors.retain(|e| !e.path.starts_with(path));
+        }
+
+        let gone: Vec<(PathBuf, W::Wd)> = subtree(
+            self.dirs.range::<Path, _>((Bound::Included(path), Bound::Unbounded)),
+            path,
+            |(p, _)| p.as_path(),
+        ).map(|(p, wd)| (p.clone(), wd.clone())).collect();
+        for (dir, wd) in gone {
+            self.dirs.remove(&dir);
+            self.wds.remove(&wd);
+            self.watches.remove(wd);
+        }
+    }
+
+    /// State that traversal would have arrived with to the watched `dir`. Unreadable
+    /// ignore files above `dir` that are not reported yet are added to errors.
+    fn state_for(&self, dir: &Path) -> DirState {
+        let root = self.roots
+            .iter()
+            .filter(|r| dir.starts_with(r))
+            .max_by_key(|r| r.components().count())
+            .map_or(dir, |r| r.as_path());
+        let mut errors = Vec::new();
+        let state = DirState::for_subdir(root, dir, &self.ignores, &mut errors);
+        let mut c = self.contents.lock().unwrap();
+        for err in errors {
+            if !c.errors.iter().any(|e| e.path == err.path) {
+                c.errors.push(err);
+            }
+        }
+        state
+    }
+
+    fn rescan(&mut self, dir: PathBuf) {
+        // Drop errors that scanning `dir` again will report anew: the ones of `dir` itself and
+        // of its entries, including subdirectories that are not watched.
+        {
+            let dirs = &self.dirs;
+            let mut c = self.contents.lock().unwrap();
+            c.errors.retain(|e| {
+                e.path != dir && e.path.ancestors().skip(1).find(|a| dirs.contains_key(*a)) != Some(dir.as_path())
+            });
+        }
+
+        let state = self.state_for(&dir);
+        self.scan(dir, state);
+    }
+
+    /// Scan roots from scratch, e.g. initially or after the kernel dropped some events.
+    fn reset(&mut self) {
+        for (_, wd) in std::mem::take(&mut self.dirs) {
+            self.watches.remove(wd);
+        }
+        self.wds.clear();
+        {
+            let mut c = self.contents.lock().unwrap();
+            c.files.clear();
+            c.errors.clear();
+            c.scanning = true;
+        }
+        self.scan_roots();
+        self.contents.lock().unwrap().scanning = false;
+    }
+}
+
+impl Watcher<Inotify> {
+    fn run(mut self) {
+        self.reset();
+        let mut buf = vec![0; 64 * 1024];
+        while !self.stop.load(Ordering::Relaxed) {
+            // Directories with new entries, rescanned once all events at hand are processed.
+            let mut dirty = BTreeSet::new();
+            let mut overflow = false;
+
+            match self.watches.read_events(&mut buf) {
+                Ok(events) => {
+                    for event in events {
+                        if event.mask.contains(EventMask::Q_OVERFLOW) {
+                            overflow = true;
+                            continue;
+                        }
+                        if event.mask.contains(EventMask::IGNORED) {
+                            if let Some(dir) = self.wds.remove(&event.wd) {
+                                self.dirs.remove(&dir);
+                            }
+                            continue;
+                        }
+                        let (dir, name) = match (self.wds.get(&event.wd), event.name) {
+                            (Some(dir), Some(name)) => (dir.clone(), name),
+                            _ => continue,
+                        };
+                        if event.mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+                            self.forget(&dir.join(name));
+                        } else if event.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                            dirty.insert(dir);
+                        }
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(IDLE_INTERVAL);
+                    continue;
+                }
+                Err(err) => {
+                    let path = self.roots.first().cloned().unwrap_or_default();
+                    self.contents.lock().unwrap().errors.push(EntryError { path, err });
+                    return;
+                }
+            }
+
+            if overflow {
+                self.reset();
+                continue;
+            }
+            for dir in dirty {
+                // Could have been removed by a later event.
+                if self.dirs.contains_key(&dir) {
+                    self.rescan(dir);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::find::test::TempTree;
+
+    /// Watches that identify directories by their canonical paths.
+    struct FakeWatches;
+
+    impl Watches for FakeWatches {
+        type Wd = PathBuf;
+
+        fn add(&mut self, dir: &Path) -> io::Result<PathBuf> {
+            dir.canonicalize()
+        }
+
+        fn remove(&mut self, _wd: PathBuf) {}
+    }
+
+    fn watcher(tree: &TempTree, opts: Opts) -> Watcher<FakeWatches> {
+        let none = || std::iter::empty::<anyhow::Result<&str>>();
+        let all = std::iter::once(Ok("*"));
+        let ignores = Ignores::new(all, none(), none(), none(), none()).unwrap();
+        let contents = Arc::new(Mutex::new(Contents::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut w = Watcher::new(vec![tree.0.clone()], Arc::new(ignores), opts, FakeWatches, contents, stop);
+        w.reset();
+        w
+    }
+
+    fn files(w: &Watcher<FakeWatches>, tree: &TempTree) -> Vec<String> {
+        let c = w.contents.lock().unwrap();
+        c.files.iter().map(|p| p.strip_prefix(&tree.0).unwrap().to_string_lossy().into_owned()).collect()
+    }
+
+    fn errors(w: &Watcher<FakeWatches>) -> Vec<PathBuf> {
+        w.contents.lock().unwrap().errors.iter().map(|e| e.path.clone()).collect()
+    }
+
+    #[test]
+    fn subtree_is_contiguous() {
+        let paths: BTreeSet<PathBuf> =
+            ["/a", "/a/b", "/a/b/c", "/a-b", "/a.txt", "/ab", "/b"].iter().map(PathBuf::from).collect();
+        let parent = Path::new("/a");
+        let sub: Vec<&PathBuf> =
+            subtree(paths.range::<Path, _>((Bound::Included(parent), Bound::Unbounded)), parent, |p| p.as_path()).collect();
+        assert_eq!(sub, [Path::new("/a"), Path::new("/a/b"), Path::new("/a/b/c")]);
+    }
+
+    #[test]
+    fn scan_and_forget() {
+        let tree = TempTree::new("index-forget", &["c.rs", "src/a.rs", "src/sub/b.rs", "src2/d.rs"]);
+        let mut w = watcher(&tree, Opts::default());
+        assert!(!w.contents.lock().unwrap().scanning);
+        assert_eq!(files(&w, &tree), ["c.rs", "src/a.rs", "src/sub/b.rs", "src2/d.rs"]);
+        assert_eq!(w.dirs.len(), 4);
+
+        w.forget(&tree.0.join("src"));
+        assert_eq!(files(&w, &tree), ["c.rs", "src2/d.rs"]);
+        assert_eq!(w.dirs.keys().collect::<Vec<_>>(), [&tree.0, &tree.0.join("src2")]);
+        assert_eq!(w.wds.len(), 2);
+    }
+
+    #[test]
+    fn rescan_finds_new_entries() {
+        let tree = TempTree::new("index-rescan", &["src/a.rs"]);
+        let mut w = watcher(&tree, Opts::default());
+        std::fs::write(tree.0.join("src/b.rs"), "").unwrap();
+        std::fs::create_dir(tree.0.join("src/new")).unwrap();
+        std::fs::write(tree.0.join("src/new/c.rs"), "").unwrap();
+
+        w.rescan(tree.0.join("src"));
+        assert_eq!(files(&w, &tree), ["src/a.rs", "src/b.rs", "src/new/c.rs"]);
+        assert!(w.dirs.contains_key(&tree.0.join("src/new")));
+    }
+
+    #[test]
+    fn rescan_replaces_errors() {
+        let tree = TempTree::new("index-errors", &["src/a.rs"]);
+        let broken = tree.0.join("src/broken");
+        std::os::unix::fs::symlink(tree.0.join("missing"), &broken).unwrap();
+        let opts = Opts { follow_symlinks: true, ..Opts::default() };
+        let mut w = watcher(&tree, opts);
+        assert_eq!(errors(&w), [broken.clone()]);
+
+        w.rescan(tree.0.join("src"));
+        assert_eq!(errors(&w), [broken.clone()]);
+
+        std::fs::remove_file(&broken).unwrap();
+        w.forget(&broken);
+        assert!(errors(&w).is_empty());
+    }
+
+    #[test]
+    fn symlinked_dir_is_watched_once() {
+        let tree = TempTree::new("index-symlink", &["src/a.rs"]);
+        std::os::unix::fs::symlink(tree.0.join("src"), tree.0.join("link")).unwrap();
+        let opts = Opts { follow_symlinks: true, ..Opts::default() };
+        let w = watcher(&tree, opts);
+        assert_eq!(w.dirs.len(), 2);
+        assert_eq!(files(&w, &tree).len(), 1);
+    }
+}
@@ -1,10 +1,14 @@
 
 #![allow(dead_code)]
 
+use std::convert::TryFrom;
+use std::io::{self, LineWriter};
 use std::iter::IntoIterator;
+use std::num::NonZeroUsize;
 use std::path::{PathBuf, Path};
 use std::result;
 use std::sync::{Arc, mpsc};
+use std::time::{Duration, SystemTime};
 
 use anyhow;
 use emacs;
@@ -14,15 +18,74 @@ use grep_regex::{RegexMatcher, RegexMatcherBuilder};
 use grep_searcher::{self, Searcher, SearcherBuilder};
 use pathdiff;
 
+pub mod completion;
+pub mod dir_cache;
 pub mod emacs_conv;
+pub mod emacs_env_ext;
 pub mod find;
 pub mod fuzzy_match;
+pub mod git_index;
+#[cfg(target_os = "linux")]
+pub mod index;
 pub mod path;
+pub mod stream;
+pub mod translit;
 
 use emacs_conv::*;
-use path::EmacsPath;
+use path::{EmacsPath, FileName};
+use stream::RecordWriter;
 
-emacs::use_symbols!(nil make_egrep_match length);
+emacs::use_symbols!(nil t make_egrep_match length pinyin kana
+                    car eval read fboundp symbol_function func_arity documentation
+                    completion_boundaries all_completions copy_sequence put_text_property
+                    completion_score score original_index alphabetical path_depth
+                    kw_field_weights => ":field-weights"
+                    kw_transliterate => ":transliterate"
+                    kw_predicate => ":predicate"
+                    kw_sort => ":sort"
+                    kw_git_ignore => ":git-ignore"
+                    kw_threads => ":threads"
+                    kw_follow_symlinks => ":follow-symlinks"
+                    kw_max_depth => ":max-depth"
+                    kw_min_depth => ":min-depth"
+                    kw_filter => ":filter"
+                    kw_min_size => ":min-size"
+                    kw_max_size => ":max-size"
+                    kw_newer_than => ":newer-than"
+                    kw_older_than => ":older-than"
+                    kw_executable => ":executable"
+                    kw_empty => ":empty"
+                    float_time
+                    kw_mode => ":mode"
+                    mode_files => "files"
+                    mode_directories => "directories"
+                    mode_all => "all"
+                    kw_same_filesystem => ":same-filesystem"
+                    kw_unibyte_file_names => ":unibyte-file-names"
+                    file directory
+                    quit
+                    kw_partial_on_quit => ":partial-on-quit"
+                    kw_cache => ":cache"
+                    kw_hidden => ":hidden"
+                    hidden_include => "include"
+                    hidden_exclude => "exclude"
+                    kw_untracked => ":untracked"
+                    kw_relative_to => ":relative-to"
+                    relative_to_root => "root"
+                    expand_file_name
+                    kw_group_by_root => ":group-by-root"
+                    kw_ignore_case => ":ignore-case"
+                    case_globs => "globs"
+                    case_ignored_files => "ignored-files"
+                    case_ignored_dirs => "ignored-dirs"
+                    case_ignored_dir_prefixes => "ignored-dir-prefixes"
+                    case_ignored_abs_dirs => "ignored-abs-dirs"
+                    user_ptr
+                    kw_globs => ":globs"
+                    kw_ignored_files => ":ignored-files"
+                    kw_ignored_dirs => ":ignored-dirs"
+                    kw_ignored_dir_prefixes => ":ignored-dir-prefixes"
+                    kw_ignored_abs_dirs => ":ignored-abs-dirs");
 
 // Emacs won't load the module without this.
 emacs::plugin_is_GPL_compatible!();
@@ -30,9 +93,51 @@ emacs::plugin_is_GPL_compatible!();
 // Register the initialization hook that Emacs will call when it loads the module.
 #[emacs::module(name = "rust_native")]
 fn init(env: &Env) -> Result<Value<'_>> {
+    // These functions took no options plist before, keep supporting calls without it.
+    for name in [
+        "score-matches",
+        "score-single-match",
+        "find-rec",
+        "find-rec-serial",
+        "git-files",
+        "grep",
+        "make-project-index",
+    ] {
+        make_last_arg_optional(env, &format!("rust-native-{}", name))?;
+    }
     Ok(nil.bind(env))
 }
 
+/// Redefine function `name`, if it’s defined, so that its last argument may be omitted
+/// and is nil then. Functions defined with `defun` always require all of their arguments.
+fn make_last_arg_optional(env: &Env, name: &str) -> Result<()> {
+    let sym = env.intern(name)?;
+    if !env.call(fboundp, (sym,))?.is_not_nil() {
+        return Ok(());
+    }
+    let f = env.call(symbol_function, (sym,))?;
+    let arity: i64 = env.call(car, (env.call(func_arity, (f,))?,))?.into_rust()?;
+    let args = (1..arity).map(|i| format!("a{}", i)).collect::<Vec<_>>().join(" ");
+    let doc: Option<String> = env.call(documentation, (f, t.bind(env)))?.into_rust()?;
+    // Signature that `defun` appends to documentation lists all arguments as required.
+    let doc = doc.map(|doc| match doc.rfind(' ') {
+        Some(i) if doc.ends_with(')') => format!("{} &optional{}", &doc[..i], &doc[i..]),
+        _ => doc,
+    });
+    let define = format!(
+        "(lambda (f doc) (defalias '{} (lambda ({} &optional opts) (funcall f {} opts)) doc))",
+        name, args, args
+    );
+    let define = env.call(eval, (env.call(read, (define,))?, t.bind(env)))?;
+    define.call((f, doc))?;
+    Ok(())
+}
+
+/// Options plist that callers may omit, nil if they do.
+fn opts_or_nil<'a>(env: &'a Env, opts: Option<Value<'a>>) -> Value<'a> {
+    opts.unwrap_or_else(|| nil.bind(env))
+}
+
 fn decode_vector_of_chars(v: Vector) -> Result<Vec<char>> {
     v.into_iter()
      .map(|x| {
@@ -44,71 +149,798 @@ fn decode_vector_of_chars(v: Vector) -> Result<Vec<char>> {
      .collect()
 }
 
+/// Decode either nil, which means default weights, or a vector of field weights where
+/// each entry is an integer, t (weight 1) or nil (field is not matched).
+fn decode_field_weights(v: Value) -> Result<Vec<fuzzy_match::FieldWeight>> {
+    if !v.is_not_nil() {
+        return Ok(Vec::new());
+    }
+    let weights: Vector = v.into_rust()?;
+    weights
+        .into_iter()
+        .map(|x| {
+            if !x.is_not_nil() {
+                Ok(0)
+            } else if x.eq(t.bind(x.env)) {
+                Ok(1)
+            } else {
+                let w: i64 = x.into_rust()?;
+                fuzzy_match::FieldWeight::try_from(w)
+                    .map_err(|_| anyhow::Error::msg(format!("Field weight out of range: {}", w)))
+            }
+        })
+        .collect()
+}
+
+/// Decode transliteration setting: nil disables it, t enables everything, otherwise
+/// it is either a symbol or a list of symbols from `pinyin` and `kana`.
+fn decode_transliteration(v: Value) -> Result<translit::Transliteration> {
+    let env = v.env;
+    if !v.is_not_nil() {
+        return Ok(translit::Transliteration::none());
+    }
+    if v.eq(t.bind(env)) {
+        return Ok(translit::Transliteration::all());
+    }
+
+    let mut res = translit::Transliteration::none();
+    let mut add = |x: Value| -> Result<()> {
+        if x.eq(pinyin.bind(env)) {
+            res.han = true;
+        } else if x.eq(kana.bind(env)) {
+            res.kana = true;
+        } else {
+            return Err(anyhow::Error::msg("Invalid transliteration, expected 'pinyin or 'kana"));
+        }
+        Ok(())
+    };
+    if is_cons(v)? {
+        for x in ListIter::new(v) {
+            add(x?)?;
+        }
+    } else {
+        add(v)?;
+    }
+    Ok(res)
+}
+
+/// Decode sort specification, a list of symbols from `score`, `length`, `original-index`,
+/// `alphabetical` and `path-depth`. Nil means default ordering: by score, then by length,
+/// then by position in the original collection.
+fn decode_sort_spec(v: Value) -> Result<Vec<fuzzy_match::SortKey>> {
+    let env = v.env;
+    if !v.is_not_nil() {
+        return Ok(fuzzy_match::DEFAULT_SORT.to_vec());
+    }
+    ListIter::new(v)
+        .map(|x| {
+            let x = x?;
+            if x.eq(score.bind(env)) {
+                Ok(fuzzy_match::SortKey::Score)
+            } else if x.eq(length.bind(env)) {
+                Ok(fuzzy_match::SortKey::Length)
+            } else if x.eq(original_index.bind(env)) {
+                Ok(fuzzy_match::SortKey::OriginalIndex)
+            } else if x.eq(alphabetical.bind(env)) {
+                Ok(fuzzy_match::SortKey::Alphabetical)
+            } else if x.eq(path_depth.bind(env)) {
+                Ok(fuzzy_match::SortKey::PathDepth)
+            } else {
+                Err(anyhow::Error::msg(
+                    "Invalid sort key, expected one of 'score, 'length, 'original-index, 'alphabetical or 'path-depth"
+                ))
+            }
+        })
+        .collect()
+}
+
+/// Haystacks is a list, vector, hash table or obarray, just like completion collections.
+/// Hash tables are matched by their string and symbol keys and obarrays by symbol names.
+///
+/// Haystacks may be strings or multi-field candidates: conses, lists or vectors of strings.
+///
+/// `input_opts` is a plist with keys
+///
+/// `:field-weights` - vector of weights of candidate fields, see `decode_field_weights`.
+/// Only fields with non-zero weight take part in matching. If weights are nil then only
+/// the first field is matched;
+///
+/// `:transliterate` - enables matching ASCII needles against romanized CJK haystacks, see
+/// `decode_transliteration`;
+///
+/// `:predicate` - only the elements that satisfy it are scored, unless it is nil;
+///
+/// `:sort` - list of keys to order results by, see `decode_sort_spec`. Sorting is stable
+/// so candidates that are equal under all keys keep their original order.
 #[defun]
 fn score_matches<'a>(
     env: &'a Env,
     input_group_seps: Vector<'a>,
     needle: String,
     haystacks: Value<'a>,
+    input_opts: Option<Value<'a>>,
 ) -> Result<Value<'a>>
 {
+    let input_opts = opts_or_nil(env, input_opts);
+    let group_seps: Vec<char> = decode_vector_of_chars(input_group_seps)?;
+    let weights = decode_field_weights(plist_lookup(input_opts, kw_field_weights.bind(env))?)?;
+    let translit = decode_transliteration(plist_lookup(input_opts, kw_transliterate.bind(env))?)?;
+    let pred = plist_lookup(input_opts, kw_predicate.bind(env))?;
+    let sort_spec = decode_sort_spec(plist_lookup(input_opts, kw_sort.bind(env))?)?;
+
+    let mut candidates = Vec::new();
+    for haystack in CollectionIter::new(haystacks, pred)? {
+        let haystack = haystack?;
+        candidates.push((decode_fields(haystack, &weights)?, haystack));
+    }
+
     let mut reuse = fuzzy_match::ReuseState::new();
+    let ranked = fuzzy_match::rank_candidates(&needle, candidates, &weights, &group_seps, translit, &sort_spec, &mut reuse);
 
-    let mut scored = Vec::new();
+    let mut results = IncrementalResList::new(env)?;
+    for x in ranked {
+        results.update(x)?;
+    }
+    results.finalize()
+}
+
+/// Returns `(score . positions)`. Positions are character indices for string haystack
+/// and `(field-index . character-index)` pairs for multi-field haystacks.
+///
+/// `input_opts` is a plist that takes `:field-weights` and `:transliterate` keys, just like
+/// in `score_matches`.
+#[defun]
+fn score_single_match<'a>(
+    env: &'a Env,
+    input_group_seps: Vector<'a>,
+    needle: String,
+    haystack: Value<'a>,
+    input_opts: Option<Value<'a>>,
+) -> Result<Value<'a>>
+{
+    let input_opts = opts_or_nil(env, input_opts);
+    let mut reuse = fuzzy_match::ReuseState::new();
 
     let group_seps: Vec<char> = decode_vector_of_chars(input_group_seps)?;
+    let translit = decode_transliteration(plist_lookup(input_opts, kw_transliterate.bind(env))?)?;
 
-    for haystack in ListIter::new(haystacks) {
-        let haystack = haystack?;
-        let haystack_str: String = haystack.clone().into_rust()?;
+    if is_string(haystack)? {
+        let haystack: String = haystack.into_rust()?;
 
-        let m: fuzzy_match::Match<()> = fuzzy_match::fuzzy_match(
+        let m: fuzzy_match::Match<Vec<fuzzy_match::StrIdx>> = fuzzy_match::fuzzy_match_translit(
             &needle,
-            &haystack_str,
+            &haystack,
             &group_seps,
-            &mut reuse
+            translit,
+            &mut reuse,
         );
 
-        scored.push((m.score, haystack_str, haystack));
+        env.cons(m.score.into_lisp(env)?, to_list(env, m.positions.into_iter())?)
+    } else {
+        let weights = decode_field_weights(plist_lookup(input_opts, kw_field_weights.bind(env))?)?;
+        let fields = decode_fields(haystack, &weights)?;
+
+        let m: fuzzy_match::FieldsMatch<Vec<fuzzy_match::StrIdx>> = fuzzy_match::fuzzy_match_fields(
+            &needle,
+            &fields,
+            &weights,
+            &group_seps,
+            translit,
+            &mut reuse,
+        );
+
+        let mut positions = IncrementalResList::new(env)?;
+        for (field, fm) in m.fields {
+            for pos in fm.positions {
+                positions.update(env.cons(field, pos)?)?;
+            }
+        }
+
+        env.cons(m.score.into_lisp(env)?, positions.finalize()?)
     }
+}
 
-    scored.sort_unstable_by(
-        |(x, xstr, _), (y, ystr, _)| {
-            // Propagate greatest score to the beginning.
-            // Resolve equal scores by comparing lengths.
-            x.cmp(y).reverse().then_with(|| xstr.len().cmp(&ystr.len()))
+/// Candidates of a completion table that fuzzy match the completion string.
+struct Completions<'a> {
+    input: completion::Input,
+    /// Sorted in default order, i.e. by decreasing score first.
+    candidates: Vec<fuzzy_match::Ranked<Value<'a>>>,
+}
+
+fn compute_completions<'a>(
+    env: &'a Env,
+    input_group_seps: Vector<'a>,
+    needle: String,
+    table: Value<'a>,
+    pred: Value<'a>,
+    point: usize,
+) -> Result<Completions<'a>>
+{
+    let group_seps: Vec<char> = decode_vector_of_chars(input_group_seps)?;
+
+    let chars: Vec<char> = needle.chars().collect();
+    let point = point.min(chars.len());
+    let before: String = chars[..point].iter().collect();
+    let after: String = chars[point..].iter().collect();
+
+    let bounds = env.call(completion_boundaries, (&before, table, pred, &after))?;
+    let start: usize = bounds.cdr::<Value>()?.car()?;
+    let end: usize = bounds.cdr::<Value>()?.cdr()?;
+
+    let prefix: String = chars[..start].iter().collect();
+    let pattern: String = chars[start..point + end].iter().collect();
+    let suffix: String = chars[point + end..].iter().collect();
+
+    let mut reuse = fuzzy_match::ReuseState::new();
+    let mut candidates = Vec::new();
+
+    for (idx, cand) in ListIter::new(env.call(all_completions, (&prefix, table, pred))?).enumerate() {
+        let cand = cand?;
+        let cand_str: String = cand.into_rust()?;
+        if !fuzzy_match::is_match(&pattern, &cand_str) {
+            continue;
+        }
+        let m: fuzzy_match::Match<()> = fuzzy_match::fuzzy_match(
+            &pattern,
+            &cand_str,
+            &group_seps,
+            &mut reuse,
+        );
+        candidates.push(fuzzy_match::Ranked {
+            score: i32::from(m.score),
+            idx,
+            len: cand_str.len(),
+            text: cand_str,
+            item: cand,
         });
+    }
+
+    fuzzy_match::sort_ranked(fuzzy_match::DEFAULT_SORT, &mut candidates);
+
+    Ok(Completions { input: completion::Input { prefix, pattern, suffix, point }, candidates })
+}
+
+/// All-completions function of a completion style. Returns candidates of `table` that fuzzy
+/// match `needle`, sorted by decreasing score, with score recorded in `completion-score`
+/// text property of the first character. Last cdr of the result is the base size.
+#[defun]
+fn completion_all<'a>(
+    env: &'a Env,
+    input_group_seps: Vector<'a>,
+    needle: String,
+    table: Value<'a>,
+    pred: Value<'a>,
+    point: usize,
+) -> Result<Value<'a>>
+{
+    let completions = compute_completions(env, input_group_seps, needle, table, pred, point)?;
+    if completions.candidates.is_empty() {
+        return Ok(nil.bind(env));
+    }
 
     let mut results = IncrementalResList::new(env)?;
-    for (_, _, s) in scored {
-        results.update(s)?;
+    for r in completions.candidates {
+        let cand = env.call(copy_sequence, (r.item,))?;
+        if !r.text.is_empty() {
+            env.call(put_text_property, (0, 1, completion_score, r.score, cand))?;
+        }
+        results.update(cand)?;
     }
-    results.finalize()
+    results.finalize_with_tail(completions.input.prefix.chars().count())
 }
 
+/// Try-completion function of a completion style. Returns nil if nothing matches, t if
+/// `needle` is the only and exact match, and `(new-string . new-point)` otherwise. New string
+/// extends the field at point with the common prefix of all matches provided it still matches
+/// them all, see `completion::try_completion`.
 #[defun]
-fn score_single_match<'a>(
+fn completion_try<'a>(
     env: &'a Env,
     input_group_seps: Vector<'a>,
     needle: String,
-    haystack: String,
+    table: Value<'a>,
+    pred: Value<'a>,
+    point: usize,
 ) -> Result<Value<'a>>
 {
-    let mut reuse = fuzzy_match::ReuseState::new();
+    let completions = compute_completions(env, input_group_seps, needle, table, pred, point)?;
+    let cands: Vec<&str> = completions.candidates.iter().map(|r| r.text.as_str()).collect();
+    match completion::try_completion(&completions.input, &cands) {
+        completion::TryCompletion::NoMatch => Ok(nil.bind(env)),
+        completion::TryCompletion::Exact => Ok(t.bind(env)),
+        completion::TryCompletion::Completed { string: new_string, point: new_point } => env.cons(new_string, new_point),
+    }
+}
 
-    let group_seps: Vec<char> = decode_vector_of_chars(input_group_seps)?;
+/// Apply ignore-related settings from traversal options, a plist accepted by `find_rec`,
+/// `find_rec_serial` and `grep`:
+///
+/// `:git-ignore` - when non-nil honor `.gitignore`, `.ignore`, `.git/info/exclude` and global
+/// git excludes in addition to explicitly specified globs.
+///
+/// `:hidden` - what to do with files and directories whose names start with a dot: `include`
+/// them (the default), `exclude` them, or exclude all but the ones whose names match a list
+/// of globs, e.g. `(".github" ".dir-locals.el")`.
+fn apply_ignore_opts(ignores: &mut find::Ignores, opts: Value) -> Result<()> {
+    let env = opts.env;
+    if plist_lookup(opts, kw_git_ignore.bind(env))?.is_not_nil() {
+        ignores.enable_git_ignore();
+    }
+    let hidden = plist_lookup(opts, kw_hidden.bind(env))?;
+    if hidden.is_not_nil() {
+        ignores.set_hidden(decode_hidden(hidden)?)?;
+    }
+    Ok(())
+}
 
-    let m: fuzzy_match::Match<Vec<fuzzy_match::StrIdx>> = fuzzy_match::fuzzy_match(
-        &needle,
-        &haystack,
-        &group_seps,
-        &mut reuse,
+/// Case sensitivity of glob lists from `:ignore-case` option: t to ignore case in all lists,
+/// or a list of symbols naming lists that ignore case, others being case-sensitive:
+/// `globs`, `ignored-files`, `ignored-dirs`, `ignored-dir-prefixes` and `ignored-abs-dirs`.
+/// By default only `globs` ignore case.
+///
+/// Within every list globs starting with `!` re-include what earlier globs matched, the last
+/// matching glob wins. Use `\\!` for globs that start with a literal exclamation mark.
+/// Negated directory globs with a slash may re-include entries within ignored directories,
+/// e.g. `:ignored-dirs '("build" "!build/generated/*.hs")`.
+fn decode_glob_case(opts: Value) -> Result<find::GlobCase> {
+    let env = opts.env;
+    let key = kw_ignore_case.bind(env);
+    if !plist_contains(opts, key)? {
+        return Ok(find::GlobCase::default());
+    }
+    let v = plist_lookup(opts, key)?;
+    if v.eq(t.bind(env)) {
+        return Ok(find::GlobCase {
+            globs: true,
+            ignored_files: true,
+            ignored_dirs: true,
+            ignored_dir_prefixes: true,
+            ignored_abs_dirs: true,
+        });
+    }
+    let mut res = find::GlobCase {
+        globs: false,
+        ignored_files: false,
+        ignored_dirs: false,
+        ignored_dir_prefixes: false,
+        ignored_abs_dirs: false,
+    };
+    for x in ListIter::new(v) {
+        let x = x?;
+        if x.eq(case_globs.bind(env)) {
+            res.globs = true;
+        } else if x.eq(case_ignored_files.bind(env)) {
+            res.ignored_files = true;
+        } else if x.eq(case_ignored_dirs.bind(env)) {
+            res.ignored_dirs = true;
+        } else if x.eq(case_ignored_dir_prefixes.bind(env)) {
+            res.ignored_dir_prefixes = true;
+        } else if x.eq(case_ignored_abs_dirs.bind(env)) {
+            res.ignored_abs_dirs = true;
+        } else {
+            return Err(anyhow::Error::msg(
+                "Invalid :ignore-case, expected t or a list of 'globs, 'ignored-files, 'ignored-dirs, 'ignored-dir-prefixes or 'ignored-abs-dirs"
+            ));
+        }
+    }
+    Ok(res)
+}
+
+/// Ignores made by `make_ignores` if `globs` is one, otherwise compiled from glob lists
+/// along with ignore-related options from `opts`, see `apply_ignore_opts`. In the former case
+/// the rest of the lists must be nil and ignore-related options must be passed to
+/// `make_ignores` instead.
+fn decode_ignores(lists: [Value; 5], opts: Value) -> Result<Arc<find::Ignores>> {
+    let env = opts.env;
+    let globs = lists[0];
+    if env.type_of(globs)?.eq(user_ptr.bind(env)) {
+        if lists[1..].iter().any(|x| x.is_not_nil()) {
+            return Err(anyhow::Error::msg("Glob lists must be nil when ignores object is passed"));
+        }
+        for (key, name) in [(kw_git_ignore, ":git-ignore"), (kw_hidden, ":hidden"), (kw_ignore_case, ":ignore-case")] {
+            if plist_contains(opts, key.bind(env))? {
+                return Err(anyhow::anyhow!("{} must be passed to make-ignores when ignores object is used", name));
+            }
+        }
+        return Ok(globs.into_rust::<&Arc<find::Ignores>>()?.clone());
+    }
+
+    let mut ignores = find::Ignores::new_with_case(
+        to_strings_iter(lists[0]),
+        to_strings_iter(lists[1]),
+        to_strings_iter(lists[2]),
+        to_strings_iter(lists[3]),
+        to_strings_iter(lists[4]),
+        decode_glob_case(opts)?,
+    )?;
+    apply_ignore_opts(&mut ignores, opts)?;
+    Ok(Arc::new(ignores))
+}
+
+/// Compile ignores once so that they can be passed to traversal functions like `find_rec`
+/// and `grep` in place of their `globs` argument, with the rest of glob lists being nil.
+/// `spec` is a plist with keys
+///
+/// `:globs` - files to report, all files if absent;
+///
+/// `:ignored-files`, `:ignored-dirs`, `:ignored-dir-prefixes`, `:ignored-abs-dirs` - what
+/// traversal functions take as positional glob lists;
+///
+/// as well as ignore-related options described in `apply_ignore_opts` and `decode_glob_case`.
+#[defun]
+fn make_ignores(spec: Value) -> Result<Arc<find::Ignores>> {
+    let env = spec.env;
+    let globs = plist_lookup(spec, kw_globs.bind(env))?;
+    let globs = if plist_contains(spec, kw_globs.bind(env))? { globs } else { env.list(("*",))? };
+    let mut ignores = find::Ignores::new_with_case(
+        to_strings_iter(globs),
+        to_strings_iter(plist_lookup(spec, kw_ignored_files.bind(env))?),
+        to_strings_iter(plist_lookup(spec, kw_ignored_dirs.bind(env))?),
+        to_strings_iter(plist_lookup(spec, kw_ignored_dir_prefixes.bind(env))?),
+        to_strings_iter(plist_lookup(spec, kw_ignored_abs_dirs.bind(env))?),
+        decode_glob_case(spec)?,
+    )?;
+    apply_ignore_opts(&mut ignores, spec)?;
+    Ok(Arc::new(ignores))
+}
+
+fn decode_hidden(v: Value) -> Result<find::Hidden> {
+    let env = v.env;
+    if v.eq(hidden_include.bind(env)) {
+        Ok(find::Hidden::Include)
+    } else if v.eq(hidden_exclude.bind(env)) {
+        Ok(find::Hidden::Exclude)
+    } else if is_cons(v)? {
+        Ok(find::Hidden::ExcludeExcept(to_strings_iter(v).collect::<Result<_>>()?))
+    } else {
+        Err(anyhow::Error::msg("Invalid :hidden, expected 'include, 'exclude or a list of globs"))
+    }
+}
+
+/// Traversal settings from options plist:
+///
+/// `:threads` - number of workers, defaults to the value set via `set_find_threads`.
+///
+/// `:follow-symlinks` - when non-nil descend into symlinked directories and report symlinked
+/// files. Broken links and directory cycles are reported as errors.
+///
+/// `:max-depth`, `:min-depth` - only report files at these depths, files directly within
+/// a root have depth 1.
+///
+/// `:filter` - plist of conditions on file metadata, see `decode_meta_filter`.
+///
+/// `:partial-on-quit` - when non-nil return results found so far if interrupted by C-g
+/// rather than signalling `quit`.
+///
+/// `:mode` - `files` (the default), `directories` or `all`. In the latter two modes every
+/// result is tagged with its kind as `(file . path)` or `(directory . path)`.
+///
+/// `:same-filesystem` - when non-nil don’t descend into directories that reside on
+/// a different filesystem than their root, e.g. `/proc` or network mounts. Such directories
+/// are reported as errors.
+///
+/// `:unibyte-file-names` - when non-nil return file names that aren’t valid UTF-8 as unibyte
+/// strings, see `decode_unibyte_file_names`.
+fn decode_find_opts(opts: Value) -> Result<find::Opts> {
+    let env = opts.env;
+    let mut res = find::Opts::default();
+    let threads = plist_lookup(opts, kw_threads.bind(env))?;
+    if threads.is_not_nil() {
+        res.threads = decode_threads(threads)?;
+    }
+    res.follow_symlinks = plist_lookup(opts, kw_follow_symlinks.bind(env))?.is_not_nil();
+    let max_depth = plist_lookup(opts, kw_max_depth.bind(env))?;
+    if max_depth.is_not_nil() {
+        res.max_depth = Some(max_depth.into_rust::<usize>()?);
+    }
+    let min_depth = plist_lookup(opts, kw_min_depth.bind(env))?;
+    if min_depth.is_not_nil() {
+        res.min_depth = min_depth.into_rust::<usize>()?;
+    }
+    let filter = plist_lookup(opts, kw_filter.bind(env))?;
+    if filter.is_not_nil() {
+        res.filter = decode_meta_filter(filter)?;
+    }
+    let mode = plist_lookup(opts, kw_mode.bind(env))?;
+    if mode.is_not_nil() {
+        res.mode = decode_mode(mode)?;
+    }
+    res.same_filesystem = plist_lookup(opts, kw_same_filesystem.bind(env))?.is_not_nil();
+    Ok(res)
+}
+
+/// Traversal settings for `grep` and `grep_async`, same as for `find_rec` except that only
+/// files can be searched so `:mode` is ignored.
+fn decode_grep_opts(opts: Value) -> Result<find::Opts> {
+    let mut res = decode_find_opts(opts)?;
+    res.mode = find::Mode::Files;
+    Ok(res)
+}
+
+fn decode_mode(v: Value) -> Result<find::Mode> {
+    let env = v.env;
+    if v.eq(mode_files.bind(env)) {
+        Ok(find::Mode::Files)
+    } else if v.eq(mode_directories.bind(env)) {
+        Ok(find::Mode::Dirs)
+    } else if v.eq(mode_all.bind(env)) {
+        Ok(find::Mode::All)
+    } else {
+        Err(anyhow::Error::msg("Invalid mode, expected one of 'files, 'directories or 'all"))
+    }
+}
+
+/// Path found by `find_rec`, tagged with its kind unless only files are being searched for.
+struct FoundEntry {
+    kind: Option<find::Kind>,
+    path: FileName,
+}
+
+impl FoundEntry {
+    /// See `decode_unibyte_file_names` for the meaning of `unibyte`.
+    fn new(opts: &find::Opts, unibyte: bool, path: PathBuf, kind: find::Kind) -> result::Result<Self, PathError> {
+        let kind = if opts.mode == find::Mode::Files { None } else { Some(kind) };
+        match FileName::new(path, unibyte) {
+            Ok(path) => Ok(FoundEntry { kind, path }),
+            Err(err) => Err(PathError::invalid_name(&err.0, err.to_string())),
+        }
+    }
+}
+
+/// Problem with a single path that was skipped, reported to Lisp as `(path kind message)`
+/// where kind is a symbol like `permission-denied` or `not-found`.
+struct PathError {
+    path: String,
+    kind: io::ErrorKind,
+    message: String,
+}
+
+impl PathError {
+    fn new(path: &Path, err: &io::Error) -> Self {
+        PathError {
+            path: path.to_string_lossy().into_owned(),
+            kind: err.kind(),
+            message: err.to_string(),
+        }
+    }
+
+    /// Name of `path` can’t be passed to Emacs, see `FileName::new`.
+    fn invalid_name(path: &Path, message: String) -> Self {
+        PathError { path: path.to_string_lossy().into_owned(), kind: io::ErrorKind::InvalidData, message }
+    }
+
+    /// Error that doesn’t relate to any particular path.
+    fn other(err: &anyhow::Error) -> Self {
+        PathError { path: String::new(), kind: io::ErrorKind::Other, message: err.to_string() }
+    }
+
+    /// Kebab-case name of the error kind, e.g. `PermissionDenied` becomes `permission-denied`.
+    fn kind_name(&self) -> String {
+        let mut res = String::new();
+        for c in format!("{:?}", self.kind).chars() {
+            if c.is_ascii_uppercase() && !res.is_empty() {
+                res.push('-');
+            }
+            res.push(c.to_ascii_lowercase());
+        }
+        res
+    }
+
+    fn write_record<W: std::io::Write>(&self, out: &mut RecordWriter<W>) -> io::Result<()> {
+        out.write("e", &[&self.path, &self.kind_name(), &self.message])
+    }
+}
+
+impl From<find::EntryError> for PathError {
+    fn from(x: find::EntryError) -> Self {
+        PathError::new(&x.path, &x.err)
+    }
+}
+
+impl<'a> emacs::IntoLisp<'a> for PathError {
+    fn into_lisp(self, env: &'a Env) -> Result<Value<'a>> {
+        let kind = env.intern(&self.kind_name())?;
+        env.list((self.path, kind, self.message))
+    }
+}
+
+fn kind_tag(kind: find::Kind) -> &'static str {
+    match kind {
+        find::Kind::File => "f",
+        find::Kind::Dir => "d",
+    }
+}
+
+impl<'a> emacs::IntoLisp<'a> for FoundEntry {
+    fn into_lisp(self, env: &'a Env) -> Result<Value<'a>> {
+        match self.kind {
+            None => self.path.into_lisp(env),
+            Some(find::Kind::File) => env.cons(file, self.path),
+            Some(find::Kind::Dir) => env.cons(directory, self.path),
+        }
+    }
+}
+
+/// Metadata filter from a plist with keys
+///
+/// `:min-size`, `:max-size` - bounds on file size in bytes, inclusive;
+///
+/// `:newer-than`, `:older-than` - bounds on modification time, any Lisp time value;
+///
+/// `:executable` - when non-nil only report files with executable bit set;
+///
+/// `:empty` - when non-nil only report empty files.
+fn decode_meta_filter(filter: Value) -> Result<find::MetaFilter> {
+    let env = filter.env;
+    let decode_size = |key: Value| -> Result<Option<u64>> {
+        let v = plist_lookup(filter, key)?;
+        if v.is_not_nil() { Ok(Some(v.into_rust::<u64>()?)) } else { Ok(None) }
+    };
+    let decode_time = |key: Value| -> Result<Option<SystemTime>> {
+        let v = plist_lookup(filter, key)?;
+        if v.is_not_nil() {
+            let secs = env.call(float_time, (v,))?.into_rust::<f64>()?;
+            let time = if secs >= 0.0 {
+                SystemTime::UNIX_EPOCH + Duration::from_secs_f64(secs)
+            } else {
+                SystemTime::UNIX_EPOCH - Duration::from_secs_f64(-secs)
+            };
+            Ok(Some(time))
+        } else {
+            Ok(None)
+        }
+    };
+    Ok(find::MetaFilter {
+        min_size: decode_size(kw_min_size.bind(env))?,
+        max_size: decode_size(kw_max_size.bind(env))?,
+        newer_than: decode_time(kw_newer_than.bind(env))?,
+        older_than: decode_time(kw_older_than.bind(env))?,
+        executable: plist_lookup(filter, kw_executable.bind(env))?.is_not_nil(),
+        empty: plist_lookup(filter, kw_empty.bind(env))?.is_not_nil(),
+    })
+}
+
+/// Whether options plist asks to return results collected so far when interrupted by C-g
+/// via `:partial-on-quit`. Has to be decoded upfront since Lisp can’t be called once quit
+/// is pending.
+fn decode_partial_on_quit(opts: Value) -> Result<bool> {
+    Ok(plist_lookup(opts, kw_partial_on_quit.bind(opts.env))?.is_not_nil())
+}
+
+/// Whether options plist asks via `:unibyte-file-names` to return file names that aren’t
+/// valid UTF-8 as unibyte strings of their raw bytes rather than reporting them as errors.
+/// Emacs passes such names to the OS unchanged, so they can be opened from Lisp. Only
+/// supported on Unix and not by functions that stream results into a process.
+fn decode_unibyte_file_names(opts: Value) -> Result<bool> {
+    Ok(plist_lookup(opts, kw_unibyte_file_names.bind(opts.env))?.is_not_nil())
+}
+
+/// Handle result of traversal that may have been interrupted by C-g. Signals `quit` unless
+/// `partial_on_quit` is set, in which case results collected so far should be returned.
+/// Partial results need Emacs 27 or later.
+fn handle_interrupt(env: &Env, res: Result<()>, partial_on_quit: bool) -> Result<()> {
+    match res {
+        Err(err) if err.is::<find::Interrupted>() => {
+            if partial_on_quit {
+                emacs_env_ext::clear_signal(env);
+                Ok(())
+            } else {
+                env.signal(quit, &[] as &[Value])
+            }
+        }
+        res => res,
+    }
+}
+
+fn decode_threads(v: Value) -> Result<NonZeroUsize> {
+    let n = v.into_rust::<i64>()?;
+    usize::try_from(n)
+        .ok()
+        .and_then(NonZeroUsize::new)
+        .ok_or_else(|| anyhow::anyhow!("Number of threads must be positive: {}", n))
+}
+
+/// Set number of workers used by `find_rec` and `grep` when `:threads` is not specified,
+/// at most 4 per available core. Nil restores the default of using all available cores.
+#[defun]
+fn set_find_threads(threads: Value) -> Result<()> {
+    let n = if threads.is_not_nil() { Some(decode_threads(threads)?) } else { None };
+    let max = find::max_default_threads();
+    if let Some(x) = n.filter(|&x| x > max) {
+        return Err(anyhow::anyhow!("Number of threads must not exceed {}: {}", max, x));
+    }
+    find::set_default_threads(n);
+    Ok(())
+}
+
+/// Set up reuse of results from previous traversals if options plist has `:cache` - a file
+/// to load directory entries from and to save them into once traversal completes.
+/// Saved entries are only used by traversals with the same roots, ignores and options.
+/// Returns the file, if any.
+fn decode_cache(roots: &[String], ignores: &find::Ignores, input_opts: Value, opts: &mut find::Opts) -> Result<Option<PathBuf>> {
+    let cache_file = plist_lookup(input_opts, kw_cache.bind(input_opts.env))?;
+    if !cache_file.is_not_nil() {
+        return Ok(None);
+    }
+    if opts.filter.is_active() {
+        return Err(anyhow::Error::msg(":cache can’t be combined with :filter"));
+    }
+    let cache_file = PathBuf::from(cache_file.into_rust::<String>()?);
+
+    let key = format!(
+        "{:?}\n{}\n{:?} {:?} {:?} {:?} {:?}",
+        roots,
+        ignores.fingerprint(),
+        opts.follow_symlinks,
+        opts.max_depth,
+        opts.min_depth,
+        opts.mode,
+        opts.same_filesystem,
     );
 
-    env.cons(m.score.into_lisp(env)?, to_list(env, m.positions.into_iter())?)
+    opts.cache = Some(Arc::new(dir_cache::DirCache::load(&cache_file, key)?));
+    Ok(Some(cache_file))
+}
+
+/// What paths reported by `find_rec` are relative to.
+enum RelativeTo {
+    Absolute,
+    Root,
+    Base(PathBuf),
+}
+
+impl RelativeTo {
+    /// Paths can be made relative to `base` only if they’re absolute, so all `roots` must be.
+    fn base(base: PathBuf, roots: &[String]) -> Result<RelativeTo> {
+        match roots.iter().find(|r| Path::new(r).is_relative()) {
+            Some(r) => Err(anyhow::anyhow!("Root {} must be absolute when :relative-to is a directory", r)),
+            None => Ok(RelativeTo::Base(base)),
+        }
+    }
+
+    fn apply(&self, root: &Path, path: PathBuf) -> PathBuf {
+        let rel = match self {
+            RelativeTo::Absolute => None,
+            RelativeTo::Root => path.strip_prefix(root).ok().map(Path::to_path_buf),
+            RelativeTo::Base(base) => pathdiff::diff_paths(&path, base),
+        };
+        rel.unwrap_or(path)
+    }
+}
+
+/// Decode `:relative-to` option: nil for absolute paths, `root` for paths relative to
+/// the root they were found under or a directory to make paths relative to. The directory
+/// is expanded with `expand-file-name` so that it may be relative to `default-directory`
+/// or start with `~`, while `roots` must be absolute in that case.
+fn decode_relative_to(opts: Value, roots: &[String]) -> Result<RelativeTo> {
+    let env = opts.env;
+    let v = plist_lookup(opts, kw_relative_to.bind(env))?;
+    if !v.is_not_nil() {
+        Ok(RelativeTo::Absolute)
+    } else if v.eq(relative_to_root.bind(env)) {
+        Ok(RelativeTo::Root)
+    } else {
+        let base: String = env.call(expand_file_name, (v,))?.into_rust()?;
+        RelativeTo::base(PathBuf::from(base), roots)
+    }
 }
 
+/// Returns `(files . errors)`. Errors are `(path kind message)` lists for entries that could
+/// not be read, where kind is a symbol like `permission-denied`.
+///
+/// In addition to options described in `decode_find_opts` the following are accepted:
+///
+/// `:cache` - file where directory entries are saved, so that subsequent calls with the same
+/// arguments only read directories whose modification time or ignore rules have changed.
+///
+/// `:relative-to` - `root` to report paths relative to the root they were found under,
+/// or a directory to report paths relative to, which requires roots to be absolute.
+/// Absolute paths are reported by default.
+///
+/// `:group-by-root` - when non-nil files are reported as a list of `(root . files)`, one
+/// for every root in the order roots were given.
 #[defun]
+#[allow(clippy::too_many_arguments)]
 fn find_rec<'a>(
     env: &'a Env,
     input_roots: Value,
@@ -117,38 +949,286 @@ fn find_rec<'a>(
     input_ignored_dir_globs: Value,
     input_ignored_dir_prefixes_globs: Value,
     input_ignored_abs_dirs: Value,
+    input_opts: Option<Value>,
 ) -> Result<Value<'a>>
 {
-    let roots_count: usize = env.call(length, (input_roots,))?.into_rust()?;
-    let roots = to_strings_iter(input_roots);
-
-    let globs = to_strings_iter(input_globs);
-    let ignored_file_globs = to_strings_iter(input_ignored_file_globs);
-    let ignored_dir_globs = to_strings_iter(input_ignored_dir_globs);
-    let ignored_dir_prefixes_globs = to_strings_iter(input_ignored_dir_prefixes_globs);
-    let ignored_abs_dirs = to_strings_iter(input_ignored_abs_dirs);
+    let input_opts = opts_or_nil(env, input_opts);
+    let roots = collect_roots(input_roots)?;
 
-    let ignores = find::Ignores::new(globs, ignored_file_globs, ignored_dir_globs, ignored_dir_prefixes_globs, ignored_abs_dirs)?;
+    let ignores = decode_ignores(
+        [input_globs, input_ignored_file_globs, input_ignored_dir_globs, input_ignored_dir_prefixes_globs, input_ignored_abs_dirs],
+        input_opts,
+    )?;
+    let mut opts = decode_find_opts(input_opts)?;
+    let partial_on_quit = decode_partial_on_quit(input_opts)?;
+    let relative_to = decode_relative_to(input_opts, &roots)?;
+    let group_by_root = plist_lookup(input_opts, kw_group_by_root.bind(env))?.is_not_nil();
+    let unibyte = decode_unibyte_file_names(input_opts)?;
+    let cache_file = decode_cache(&roots, &ignores, input_opts, &mut opts)?;
 
     let mut s = IncrementalResErrList::new(env)?;
+    let mut groups = Vec::new();
+    if group_by_root {
+        for r in &roots {
+            groups.push((PathBuf::from(r), IncrementalResList::new(env)?));
+        }
+    }
 
-    find::find_rec(
-        roots,
-        roots_count,
+    let res = find::find_rec(
+        roots.iter().map(Ok),
+        roots.len(),
         &ignores,
+        &opts,
         || Ok(()),
-        |_state, _orig_root: (), x, chan| {
-            chan.send(path_to_string(x)).map_err(anyhow::Error::new)
+        |_state, orig_root: Arc<PathBuf>, x, kind, chan| {
+            let x = relative_to.apply(&orig_root, x);
+            chan.send((orig_root, FoundEntry::new(&opts, unibyte, x, kind))).map_err(anyhow::Error::new)
+        },
+        |orig_root, err, chan| {
+            chan.send((orig_root, Err(PathError::from(err)))).map_err(anyhow::Error::new)
+        },
+        |(orig_root, y)| match y {
+            Ok(x) if group_by_root => match groups.iter_mut().find(|(r, _)| *r == *orig_root) {
+                Some((_, files)) => files.update(x),
+                None => Err(anyhow::anyhow!("Invariant violation: unknown root {:?}", orig_root)),
+            },
+            y => s.update(y),
         },
-        |y| s.update(y)
+        || emacs_env_ext::should_quit(env),
+    );
+    // Interrupted traversal leaves out directories that should stay cached.
+    if let (Ok(()), Some(path), Some(cache)) = (&res, &cache_file, &opts.cache) {
+        cache.save(path)?;
+    }
+    handle_interrupt(env, res, partial_on_quit)?;
+
+    let (files, errs) = s.finalize()?;
+    if group_by_root {
+        let mut res = IncrementalResList::new(env)?;
+        for (root, files) in groups {
+            res.update(env.cons(root.to_string_lossy().into_owned(), files.finalize()?)?)?;
+        }
+        env.cons(res.finalize()?, errs)
+    } else {
+        env.cons(files, errs)
+    }
+}
+
+/// Find files like `find_rec` and fuzzy match their paths, relative to the root they were
+/// found under, against `needle` right away. Returns `(files . errors)` where files are
+/// the `limit` best matches, or all matches if it’s nil, sorted by decreasing score, then
+/// by length, then alphabetically.
+///
+/// Options are the same as for `find_rec`, `:relative-to` included, except that results
+/// can’t be grouped by root.
+#[defun]
+#[allow(clippy::too_many_arguments)]
+fn find_rec_fuzzy<'a>(
+    env: &'a Env,
+    input_roots: Value,
+    input_globs: Value,
+    input_ignored_file_globs: Value,
+    input_ignored_dir_globs: Value,
+    input_ignored_dir_prefixes_globs: Value,
+    input_ignored_abs_dirs: Value,
+    input_opts: Option<Value>,
+    input_group_seps: Vector<'a>,
+    needle: String,
+    limit: Option<usize>,
+) -> Result<Value<'a>>
+{
+    let input_opts = opts_or_nil(env, input_opts);
+    let roots = collect_roots(input_roots)?;
+
+    let ignores = decode_ignores(
+        [input_globs, input_ignored_file_globs, input_ignored_dir_globs, input_ignored_dir_prefixes_globs, input_ignored_abs_dirs],
+        input_opts,
+    )?;
+    let opts = decode_find_opts(input_opts)?;
+    let partial_on_quit = decode_partial_on_quit(input_opts)?;
+    let relative_to = decode_relative_to(input_opts, &roots)?;
+    let unibyte = decode_unibyte_file_names(input_opts)?;
+    let group_seps: Vec<char> = decode_vector_of_chars(input_group_seps)?;
+
+    let (res, matches, errors) = rank_found(
+        &roots,
+        &ignores,
+        &opts,
+        unibyte,
+        &relative_to,
+        &needle,
+        &group_seps,
+        limit,
+        || emacs_env_ext::should_quit(env),
+    );
+    handle_interrupt(env, res, partial_on_quit)?;
+
+    let mut s = IncrementalResErrList::new(env)?;
+    for m in matches {
+        let (kind, path) = m.item;
+        s.update(FoundEntry::new(&opts, unibyte, path, kind))?;
+    }
+    for err in errors {
+        s.update(Err::<FoundEntry, _>(err))?;
+    }
+    let (files, errs) = s.finalize()?;
+    env.cons(files, errs)
+}
+
+type RankedEntry = fuzzy_match::Ranked<(find::Kind, PathBuf)>;
+
+/// Entries under `roots` whose paths relative to their root match `needle`, best first and
+/// at most `limit` of them, along with errors. Entries found before traversal failed or was
+/// interrupted are returned as well.
+#[allow(clippy::too_many_arguments)]
+fn rank_found(
+    roots: &[String],
+    ignores: &find::Ignores,
+    opts: &find::Opts,
+    unibyte: bool,
+    relative_to: &RelativeTo,
+    needle: &str,
+    group_seps: &[char],
+    limit: Option<usize>,
+    should_quit: impl FnMut() -> bool,
+) -> (Result<()>, Vec<RankedEntry>, Vec<PathError>)
+{
+    let mut matches: Vec<RankedEntry> = Vec::new();
+    let mut errors = Vec::new();
+
+    let res = find::find_rec(
+        roots.iter().map(Ok),
+        roots.len(),
+        ignores,
+        opts,
+        || Ok(fuzzy_match::ReuseState::new()),
+        |reuse, orig_root: Arc<PathBuf>, x, kind, chan| {
+            let rel = x.strip_prefix(&*orig_root).unwrap_or(&x);
+            let text = match rel.to_str() {
+                Some(rel) => rel.to_string(),
+                // Raw bytes are returned as is, only matching needs text.
+                None if unibyte => rel.to_string_lossy().into_owned(),
+                // Report invalid file name the same way `find_rec` does.
+                None => return match FoundEntry::new(opts, unibyte, x, kind) {
+                    Ok(_) => Ok(()),
+                    Err(err) => chan.send(Err(err)).map_err(anyhow::Error::new),
+                },
+            };
+            if !fuzzy_match::is_match(needle, &text) {
+                return Ok(());
+            }
+            let m: fuzzy_match::Match<()> = fuzzy_match::fuzzy_match(needle, &text, group_seps, reuse);
+            let path = relative_to.apply(&orig_root, x);
+            chan.send(Ok(fuzzy_match::Ranked {
+                score: i32::from(m.score),
+                idx: 0,
+                len: text.len(),
+                text,
+                item: (kind, path),
+            })).map_err(anyhow::Error::new)
+        },
+        |_orig_root, err, chan| {
+            chan.send(Err(PathError::from(err))).map_err(anyhow::Error::new)
+        },
+        |y| {
+            match y {
+                Ok(m) => matches.push(m),
+                Err(err) => errors.push(err),
+            }
+            Ok(())
+        },
+        should_quit,
+    );
+
+    // Order of arrival varies between runs so break ties alphabetically.
+    let sort_keys = &[fuzzy_match::SortKey::Score, fuzzy_match::SortKey::Length, fuzzy_match::SortKey::Alphabetical];
+    if let Some(k) = limit {
+        if k < matches.len() {
+            matches.select_nth_unstable_by(k, |x, y| fuzzy_match::compare_ranked(sort_keys, x, y));
+            matches.truncate(k);
+        }
+    }
+    fuzzy_match::sort_ranked(sort_keys, &mut matches);
+    (res, matches, errors)
+}
+
+/// Files tracked in git repository whose work tree is at `input_root`, read straight from
+/// its index without running git. Returns `(files . errors)` like `find_rec`. Only files
+/// matching globs are reported, `.gitignore` doesn’t apply to tracked files.
+///
+/// Options are the same as for `find_rec`, plus `:untracked` - when non-nil also report
+/// files that are not tracked but not ignored either, found by traversing the work tree with
+/// `.gitignore` and friends honored and `.git` skipped. Other traversal options only apply
+/// to that traversal.
+#[defun]
+#[allow(clippy::too_many_arguments)]
+fn git_files<'a>(
+    env: &'a Env,
+    input_root: String,
+    input_globs: Value,
+    input_ignored_file_globs: Value,
+    input_ignored_dir_globs: Value,
+    input_ignored_dir_prefixes_globs: Value,
+    input_ignored_abs_dirs: Value,
+    input_opts: Option<Value>,
+) -> Result<Value<'a>>
+{
+    let input_opts = opts_or_nil(env, input_opts);
+    let mut ignores = decode_ignores(
+        [input_globs, input_ignored_file_globs, input_ignored_dir_globs, input_ignored_dir_prefixes_globs, input_ignored_abs_dirs],
+        input_opts,
     )?;
+    let opts = decode_find_opts(input_opts)?;
+    let partial_on_quit = decode_partial_on_quit(input_opts)?;
+    let untracked = plist_lookup(input_opts, kw_untracked.bind(env))?.is_not_nil();
+    let unibyte = decode_unibyte_file_names(input_opts)?;
+
+    let root = PathBuf::from(&input_root);
+    let mut found: std::collections::BTreeSet<PathBuf> = git_index::tracked_files(&root)?
+        .into_iter()
+        .map(|p| root.join(p))
+        .filter(|p| ignores.is_file_wanted(&root, p))
+        .collect();
+    let mut errors = Vec::new();
+
+    if untracked {
+        Arc::make_mut(&mut ignores).enable_git_ignore();
+        let res = find::find_rec(
+            std::iter::once(Ok(&input_root)),
+            1,
+            &ignores,
+            &find::Opts { mode: find::Mode::Files, ..opts.clone() },
+            || Ok(()),
+            |_state, _orig_root: (), x, _kind, chan| chan.send(Ok(x)).map_err(anyhow::Error::new),
+            |_orig_root, err, chan| chan.send(Err(PathError::from(err))).map_err(anyhow::Error::new),
+            |y| {
+                match y {
+                    Ok(x) => {
+                        found.insert(x);
+                    }
+                    Err(err) => errors.push(err),
+                }
+                Ok(())
+            },
+            || emacs_env_ext::should_quit(env),
+        );
+        handle_interrupt(env, res, partial_on_quit)?;
+    }
 
+    let mut s = IncrementalResErrList::new(env)?;
+    for x in found {
+        s.update(FoundEntry::new(&opts, unibyte, x, find::Kind::File))?;
+    }
+    for err in errors {
+        s.update(Err::<FoundEntry, _>(err))?;
+    }
     let (files, errs) = s.finalize()?;
     env.cons(files, errs)
 }
 
 // Define a function callable by Lisp.
 #[defun]
+#[allow(clippy::too_many_arguments)]
 fn find_rec_serial<'a>(
     env: &'a Env,
     input_roots: Value,
@@ -157,29 +1237,46 @@ fn find_rec_serial<'a>(
     input_ignored_dir_globs: Value,
     input_ignored_dir_prefixes_globs: Value,
     input_ignored_abs_dirs: Value,
+    input_opts: Option<Value>,
 ) -> Result<Value<'a>>
 {
+    let input_opts = opts_or_nil(env, input_opts);
     let roots = to_strings_iter(input_roots);
-    let globs = to_strings_iter(input_globs);
-    let ignored_file_globs = to_strings_iter(input_ignored_file_globs);
-    let ignored_dir_globs = to_strings_iter(input_ignored_dir_globs);
-    let ignored_dir_prefixes_globs = to_strings_iter(input_ignored_dir_prefixes_globs);
-    let ignored_abs_dirs = to_strings_iter(input_ignored_abs_dirs);
-
-    let ignores = &find::Ignores::new(globs, ignored_file_globs, ignored_dir_globs, ignored_dir_prefixes_globs, ignored_abs_dirs)?;
+    let ignores = decode_ignores(
+        [input_globs, input_ignored_file_globs, input_ignored_dir_globs, input_ignored_dir_prefixes_globs, input_ignored_abs_dirs],
+        input_opts,
+    )?;
+    let ignores = &ignores;
+    let opts = decode_find_opts(input_opts)?;
 
-    let mut local_queue: Vec<PathBuf> = Vec::new();
+    let mut local_queue: Vec<(PathBuf, find::DirState)> = Vec::new();
+    let mut root_errors = Vec::new();
     for r in roots {
         let path = std::path::PathBuf::from(std::ffi::OsString::from(r?));
-        if !ignores.ignored_dirs.abs.is_match(&path) && !ignores.ignored_dirs.rel.is_match(&path) {
-            local_queue.push(path);
+        if !ignores.ignored_dirs.is_path_match(&path) {
+            let state = find::DirState::for_root(&path, ignores, &mut root_errors);
+            local_queue.push((path, state));
         }
     }
 
+    let partial_on_quit = decode_partial_on_quit(input_opts)?;
+    let unibyte = decode_unibyte_file_names(input_opts)?;
+
     let mut s = IncrementalResErrList::new(env)?;
+    for err in root_errors {
+        s.update(Err::<FoundEntry, _>(PathError::from(err)))?;
+    }
+
+    let mut quit_poller = find::QuitPoller::new(|| emacs_env_ext::should_quit(env));
+    let mut res = Ok(());
 
     loop {
-        let root: PathBuf =
+        if quit_poller.poll() {
+            res = Err(anyhow::Error::new(find::Interrupted));
+            break;
+        }
+
+        let (root, state) =
             match local_queue.pop() {
                 Some(x) => x,
                 None => break,
@@ -187,16 +1284,22 @@ fn find_rec_serial<'a>(
 
         find::visit_dir(
             root,
+            &state,
             &ignores,
-            |p| local_queue.push(p),
-            |x| s.update(path_to_string(x)),
+            &opts,
+            |p, child_state| local_queue.push((p, child_state)),
+            |x| s.update(x.map_err(PathError::from).and_then(|(p, kind)| FoundEntry::new(&opts, unibyte, p, kind))),
         )?
     }
+    handle_interrupt(env, res, partial_on_quit)?;
     let (files, errs) = s.finalize()?;
     env.cons(files, errs)
 }
 
+/// Returns `(matches . errors)` where errors are `(path kind message)` lists, like
+/// in `find_rec`, for files that could not be searched.
 #[defun]
+#[allow(clippy::too_many_arguments)]
 fn grep<'a>(
     env: &'a Env,
     input_roots: Value,
@@ -207,59 +1310,343 @@ fn grep<'a>(
     input_ignored_dir_prefixes_globs: Value,
     input_ignored_abs_dirs: Value,
     input_case_insensitive: Value,
+    input_opts: Option<Value>,
 ) -> Result<Value<'a>>
 {
+    let input_opts = opts_or_nil(env, input_opts);
     let roots_count: usize = env.call(length, (input_roots,))?.into_rust()?;
     let roots = to_strings_iter(input_roots);
 
-    let globs = to_strings_iter(input_globs);
-    let ignored_file_globs = to_strings_iter(input_ignored_file_globs);
-    let ignored_dir_globs = to_strings_iter(input_ignored_dir_globs);
-    let ignored_dir_prefixes_globs = to_strings_iter(input_ignored_dir_prefixes_globs);
-    let ignored_abs_dirs = to_strings_iter(input_ignored_abs_dirs);
-
     let case_insensitive = input_case_insensitive.is_not_nil();
 
-    let ignores = find::Ignores::new(globs, ignored_file_globs, ignored_dir_globs, ignored_dir_prefixes_globs, ignored_abs_dirs)?;
+    let ignores = decode_ignores(
+        [input_globs, input_ignored_file_globs, input_ignored_dir_globs, input_ignored_dir_prefixes_globs, input_ignored_abs_dirs],
+        input_opts,
+    )?;
+    let opts = decode_grep_opts(input_opts)?;
 
-    let mut results = IncrementalResList::new(env)?;
+    let partial_on_quit = decode_partial_on_quit(input_opts)?;
+    let unibyte = decode_unibyte_file_names(input_opts)?;
 
-    find::find_rec(
+    let mut results = IncrementalResErrList::new(env)?;
+
+    let res = find::find_rec(
         roots,
         roots_count,
         &ignores,
-        || {
-            let searcher = SearcherBuilder::new()
-                .line_number(true)
-                .multi_line(true)
-                .memory_map(unsafe { grep_searcher::MmapChoice::auto() })
-                .binary_detection(grep_searcher::BinaryDetection::none())
-                .build();
-
-            let matcher = RegexMatcherBuilder::new()
-                .case_insensitive(case_insensitive)
-                .multi_line(true)
-                .build(&regexp)?;
-
-            Ok((searcher, matcher))
-        },
-        |(searcher, matcher), orig_root: Arc<PathBuf>, path, results| {
-            let sink = GrepSink {
-                rel_path_cache: None,
-                abs_path_cache: None,
-                abs_path: &path,
-                orig_root: &*orig_root,
-                matcher: &matcher,
-                results: &results,
-            };
-            searcher.search_path(&*matcher, &path, sink).map_err(|x| x.err)
-        },
+        &opts,
+        || make_grep_searcher(&regexp, case_insensitive),
+        |state, orig_root, path, kind, results| grep_file(state, unibyte, orig_root, path, kind, results),
+        report_grep_error,
         |m| {
             results.update(m)
+        },
+        || emacs_env_ext::should_quit(env),
+    );
+    handle_interrupt(env, res, partial_on_quit)?;
+
+    let (matches, errs) = results.finalize()?;
+    env.cons(matches, errs)
+}
+
+type GrepResult = result::Result<Match, PathError>;
+
+fn report_grep_error(
+    _orig_root: Arc<PathBuf>,
+    err: find::EntryError,
+    results: &mut mpsc::SyncSender<GrepResult>,
+) -> Result<()> {
+    results.send(Err(PathError::from(err))).map_err(anyhow::Error::new)
+}
+
+fn make_grep_searcher(regexp: &str, case_insensitive: bool) -> Result<(Searcher, RegexMatcher)> {
+    let searcher = SearcherBuilder::new()
+        .line_number(true)
+        .multi_line(true)
+        .memory_map(unsafe { grep_searcher::MmapChoice::auto() })
+        .binary_detection(grep_searcher::BinaryDetection::none())
+        .build();
+
+    let matcher = RegexMatcherBuilder::new()
+        .case_insensitive(case_insensitive)
+        .multi_line(true)
+        .build(regexp)?;
+
+    Ok((searcher, matcher))
+}
+
+/// See `decode_unibyte_file_names` for the meaning of `unibyte`.
+fn grep_file(
+    (searcher, matcher): &mut (Searcher, RegexMatcher),
+    unibyte: bool,
+    orig_root: Arc<PathBuf>,
+    path: PathBuf,
+    _kind: find::Kind,
+    results: &mut mpsc::SyncSender<GrepResult>,
+) -> Result<()> {
+    let sink = GrepSink {
+        rel_path_cache: None,
+        abs_path_cache: None,
+        abs_path: &path,
+        orig_root: &orig_root,
+        unibyte,
+        matcher,
+        results,
+    };
+    match searcher.search_path(&*matcher, &path, sink) {
+        Ok(()) => Ok(()),
+        // File became unreadable, report it and move on.
+        Err(Error { err }) => match err.downcast::<io::Error>() {
+            Ok(io_err) => results.send(Err(PathError::new(&path, &io_err))).map_err(anyhow::Error::new),
+            // Matches can’t be reported but other files may still be searched.
+            Err(err) => match err.downcast::<InvalidFileName>() {
+                Ok(InvalidFileName(message)) => {
+                    results.send(Err(PathError::invalid_name(&path, message))).map_err(anyhow::Error::new)
+                }
+                Err(err) => Err(err),
+            },
+        },
+    }
+}
+
+fn collect_roots(input_roots: Value) -> Result<Vec<String>> {
+    to_strings_iter(input_roots).collect()
+}
+
+/// Like `find_rec` but returns immediately and writes results into `process`, a pipe process
+/// created by `make-pipe-process`, as soon as they’re found. Requires Emacs 28.
+///
+/// Every line of output is a record of tab-separated fields, first of which is a tag:
+/// `f<TAB>path` for files, `d<TAB>path` for directories and `e<TAB>path<TAB>kind<TAB>message`
+/// for errors, where kind is as in `find_rec` errors and path is empty if the error doesn’t
+/// concern a particular file.
+/// Backslashes, tabs and newlines within fields are escaped as `\\`, `\t` and `\n`.
+/// The channel is closed once traversal finishes.
+#[defun]
+#[allow(clippy::too_many_arguments)]
+fn find_rec_async(
+    input_roots: Value,
+    input_globs: Value,
+    input_ignored_file_globs: Value,
+    input_ignored_dir_globs: Value,
+    input_ignored_dir_prefixes_globs: Value,
+    input_ignored_abs_dirs: Value,
+    input_opts: Option<Value>,
+    process: Value,
+) -> Result<()>
+{
+    let input_opts = opts_or_nil(input_roots.env, input_opts);
+    let roots = collect_roots(input_roots)?;
+
+    let ignores = decode_ignores(
+        [input_globs, input_ignored_file_globs, input_ignored_dir_globs, input_ignored_dir_prefixes_globs, input_ignored_abs_dirs],
+        input_opts,
+    )?;
+    let opts = decode_find_opts(input_opts)?;
+
+    let mut out = RecordWriter::new(LineWriter::new(emacs_env_ext::open_channel(process)?));
+
+    std::thread::spawn(move || {
+        let roots_count = roots.len();
+        let res = find::find_rec(
+            roots.into_iter().map(Ok),
+            roots_count,
+            &ignores,
+            &opts,
+            || Ok(()),
+            |_state, _orig_root: (), x, kind, chan| {
+                chan.send(FoundEntry::new(&opts, false, x, kind).map(|p| (kind, p.path))).map_err(anyhow::Error::new)
+            },
+            |_orig_root, err, chan| {
+                chan.send(Err(PathError::from(err))).map_err(anyhow::Error::new)
+            },
+            |y| {
+                match y {
+                    Ok((kind, p)) => out.write(kind_tag(kind), &[&p.to_str_lossy()])?,
+                    Err(err) => err.write_record(&mut out)?,
+                }
+                Ok(())
+            },
+            // Stopped by closing the pipe, which fails the next write.
+            || false,
+        );
+        if let Err(err) = res {
+            let _ = PathError::other(&err).write_record(&mut out);
+        }
+        let _ = out.flush();
+    });
+
+    Ok(())
+}
+
+/// Like `grep` but returns immediately and writes matches into `process`, a pipe process
+/// created by `make-pipe-process`, as soon as they’re found. Requires Emacs 28.
+///
+/// Every line of output is a record of tab-separated fields, first of which is a tag:
+/// `m<TAB>abs-path<TAB>rel-path<TAB>line<TAB>column<TAB>prefix<TAB>body<TAB>suffix` for
+/// matches and errors as in `find_rec_async`. Fields are escaped as in `find_rec_async` too.
+/// The channel is closed once search finishes.
+#[defun]
+#[allow(clippy::too_many_arguments)]
+fn grep_async(
+    input_roots: Value,
+    regexp: String,
+    input_globs: Value,
+    input_ignored_file_globs: Value,
+    input_ignored_dir_globs: Value,
+    input_ignored_dir_prefixes_globs: Value,
+    input_ignored_abs_dirs: Value,
+    input_case_insensitive: Value,
+    input_opts: Option<Value>,
+    process: Value,
+) -> Result<()>
+{
+    let input_opts = opts_or_nil(input_roots.env, input_opts);
+    let roots = collect_roots(input_roots)?;
+
+    let case_insensitive = input_case_insensitive.is_not_nil();
+
+    let ignores = decode_ignores(
+        [input_globs, input_ignored_file_globs, input_ignored_dir_globs, input_ignored_dir_prefixes_globs, input_ignored_abs_dirs],
+        input_opts,
+    )?;
+    let opts = decode_grep_opts(input_opts)?;
+
+    // Report invalid regexp right away rather than through the pipe.
+    make_grep_searcher(&regexp, case_insensitive)?;
+
+    let mut out = RecordWriter::new(LineWriter::new(emacs_env_ext::open_channel(process)?));
+
+    std::thread::spawn(move || {
+        let roots_count = roots.len();
+        let res = find::find_rec(
+            roots.into_iter().map(Ok),
+            roots_count,
+            &ignores,
+            &opts,
+            || make_grep_searcher(&regexp, case_insensitive),
+            |state, orig_root, path, kind, results| grep_file(state, false, orig_root, path, kind, results),
+            report_grep_error,
+            |m| {
+                match m {
+                    Ok(m) => m.write_record(&mut out)?,
+                    Err(err) => err.write_record(&mut out)?,
+                }
+                Ok(())
+            },
+            || false,
+        );
+        if let Err(err) = res {
+            let _ = PathError::other(&err).write_record(&mut out);
         }
+        let _ = out.flush();
+    });
+
+    Ok(())
+}
+
+/// Build an index of files under roots that `find_rec` with the same arguments would find,
+/// and keep it up to date in the background as files are created, deleted and renamed.
+/// Roots are scanned in the background too, so this returns right away. Query it with
+/// `project_index_files`, which returns files found so far while `project_index_scanning_p`
+/// is true. Watching stops once the index is garbage collected. Linux only.
+#[cfg(target_os = "linux")]
+#[defun(user_ptr)]
+fn make_project_index(
+    input_roots: Value,
+    input_globs: Value,
+    input_ignored_file_globs: Value,
+    input_ignored_dir_globs: Value,
+    input_ignored_dir_prefixes_globs: Value,
+    input_ignored_abs_dirs: Value,
+    input_opts: Option<Value>,
+) -> Result<index::ProjectIndex>
+{
+    let input_opts = opts_or_nil(input_roots.env, input_opts);
+    let roots = collect_roots(input_roots)?.into_iter().map(PathBuf::from).collect();
+
+    let ignores = decode_ignores(
+        [input_globs, input_ignored_file_globs, input_ignored_dir_globs, input_ignored_dir_prefixes_globs, input_ignored_abs_dirs],
+        input_opts,
     )?;
+    let opts = decode_find_opts(input_opts)?;
+
+    Ok(index::ProjectIndex::new(roots, ignores, opts)?)
+}
+
+/// Current files of an index made by `make_project_index`. If `needle` is nil then all
+/// files are returned in alphabetical order, otherwise only the ones that fuzzy match it,
+/// sorted by decreasing score. Files are matched by their path relative to the root.
+#[cfg(target_os = "linux")]
+#[defun]
+fn project_index_files<'a>(
+    env: &'a Env,
+    index: &index::ProjectIndex,
+    input_group_seps: Vector<'a>,
+    needle: Value<'a>,
+) -> Result<Value<'a>>
+{
+    let needle: Option<String> = needle.into_rust()?;
+    let group_seps: Vec<char> = decode_vector_of_chars(input_group_seps)?;
+
+    let paths: Vec<String> = index.with_contents(|files, _errors| {
+        let mut reuse = fuzzy_match::ReuseState::new();
+        let needle = match needle {
+            None => return files.iter().map(|p| p.to_string_lossy().into_owned()).collect(),
+            Some(x) => x,
+        };
+        let mut candidates = Vec::new();
+        for (idx, path) in files.iter().enumerate() {
+            let rel = index
+                .roots()
+                .iter()
+                .find_map(|r| path.strip_prefix(r).ok())
+                .unwrap_or(path);
+            let text = rel.to_string_lossy();
+            if !fuzzy_match::is_match(&needle, &text) {
+                continue;
+            }
+            let m: fuzzy_match::Match<()> = fuzzy_match::fuzzy_match(&needle, &text, &group_seps, &mut reuse);
+            candidates.push(fuzzy_match::Ranked {
+                score: i32::from(m.score),
+                idx,
+                len: text.len(),
+                text: text.into_owned(),
+                item: path,
+            });
+        }
+        fuzzy_match::sort_ranked(fuzzy_match::DEFAULT_SORT, &mut candidates);
+        candidates.into_iter().map(|r| r.item.to_string_lossy().into_owned()).collect()
+    });
+
+    let mut results = IncrementalResList::new(env)?;
+    for p in paths {
+        results.update(p.into_lisp(env)?)?;
+    }
+    results.finalize()
+}
+
+/// Whether an index made by `make_project_index` is still scanning its roots, so that
+/// `project_index_files` may miss some files.
+#[cfg(target_os = "linux")]
+#[defun]
+fn project_index_scanning_p(index: &index::ProjectIndex) -> Result<bool> {
+    Ok(index.is_scanning())
+}
 
-    results.finalize()?.into_lisp(env)
+/// Errors encountered by an index made by `make_project_index` so far, in the same format
+/// as `find_rec` errors.
+#[cfg(target_os = "linux")]
+#[defun]
+fn project_index_errors<'a>(env: &'a Env, index: &index::ProjectIndex) -> Result<Value<'a>> {
+    let errors: Vec<PathError> = index.with_contents(|_files, errors| {
+        errors.iter().map(|e| PathError::new(&e.path, &e.err)).collect()
+    });
+    let mut results = IncrementalResList::new(env)?;
+    for err in errors {
+        results.update(err.into_lisp(env)?)?;
+    }
+    results.finalize()
 }
 
 struct Match {
@@ -288,6 +1675,22 @@ impl<'a> emacs::IntoLisp<'a> for Match {
     }
 }
 
+impl Match {
+    fn write_record<W: std::io::Write>(&self, out: &mut RecordWriter<W>) -> std::io::Result<()> {
+        out.write(
+            "m",
+            &[&self.abs_path.as_str(),
+              &self.rel_path.as_str(),
+              &self.line.to_string(),
+              &self.column.to_string(),
+              &self.prefix,
+              &self.body,
+              &self.suffix,
+            ]
+        )
+    }
+}
+
 struct GrepSink<'a, 'b, 'c> {
     // Cached relative path of currently processed file.
     rel_path_cache: Option<Arc<EmacsPath>>,
@@ -295,8 +1698,9 @@ struct GrepSink<'a, 'b, 'c> {
     abs_path_cache: Option<Arc<EmacsPath>>,
     abs_path: &'a Path,
     orig_root: &'a Path,
+    unibyte: bool,
     matcher: &'b RegexMatcher,
-    results: &'c mpsc::SyncSender<Match>,
+    results: &'c mpsc::SyncSender<GrepResult>,
 }
 
 struct Error {
@@ -312,12 +1716,33 @@ impl Error {
             err: emacs::Error::msg(msg)
         }
     }
+
+    fn invalid_name(msg: String) -> Self {
+        Error { err: emacs::Error::new(InvalidFileName(msg)) }
+    }
+}
+
+/// Matched file’s name can’t be passed to Emacs.
+#[derive(Debug)]
+struct InvalidFileName(String);
+
+impl std::fmt::Display for InvalidFileName {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
+impl std::error::Error for InvalidFileName {}
+
 impl grep_searcher::SinkError for Error {
     fn error_message<T: std::fmt::Display>(message: T) -> Self {
         Error::msg(message.to_string())
     }
+
+    // Keep the original error so that unreadable files can be told apart from other failures.
+    fn error_io(err: io::Error) -> Self {
+        Error { err: emacs::Error::new(err) }
+    }
 }
 
 impl<'a, 'b, 'c> grep_searcher::Sink for GrepSink<'a, 'b, 'c> {
@@ -338,7 +1763,7 @@ impl<'a, 'b, 'c> grep_searcher::Sink for GrepSink<'a, 'b, 'c> {
                     ))),
                     Some(x) => x,
                 };
-                self.rel_path_cache = Some(Arc::new(EmacsPath::new(path).map_err(Error::msg)?));
+                self.rel_path_cache = Some(Arc::new(EmacsPath::new(path, self.unibyte).map_err(|err| Error::invalid_name(err.to_string()))?));
                 self.rel_path_cache.as_ref().unwrap()
             }
         };
@@ -346,7 +1771,7 @@ impl<'a, 'b, 'c> grep_searcher::Sink for GrepSink<'a, 'b, 'c> {
         let abs_path = match self.abs_path_cache {
             Some(ref x) => x,
             None => {
-                self.abs_path_cache = Some(Arc::new(EmacsPath::new(self.abs_path.to_owned()).map_err(Error::msg)?));
+                self.abs_path_cache = Some(Arc::new(EmacsPath::new(self.abs_path.to_owned(), self.unibyte).map_err(|err| Error::invalid_name(err.to_string()))?));
                 self.abs_path_cache.as_ref().unwrap()
             }
         };
@@ -360,7 +1785,7 @@ impl<'a, 'b, 'c> grep_searcher::Sink for GrepSink<'a, 'b, 'c> {
 
         let column = submatch.start() as u16;
 
-        self.results.send(Match {
+        self.results.send(Ok(Match {
             line,
             column,
             prefix: prefix.to_string(),
@@ -368,8 +1793,98 @@ impl<'a, 'b, 'c> grep_searcher::Sink for GrepSink<'a, 'b, 'c> {
             suffix: suffix.to_string(),
             rel_path: rel_path.clone(),
             abs_path: abs_path.clone(),
-        }).map_err(|err| Error::msg(format!("Failed to send match: {}", err)))?;
+        })).map_err(|err| Error::msg(format!("Failed to send match: {}", err)))?;
 
         Ok(true)
     }
 }
+
+#[cfg(all(test, target_family = "unix"))]
+mod test {
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+    use crate::find::test::TempTree;
+
+    /// Grep a file with Latin-1 name and then a file with a valid one.
+    fn grep_latin1_name(unibyte: bool) -> (PathBuf, Vec<GrepResult>) {
+        let tree = TempTree::new(&format!("grep-latin1-{}", unibyte), &["valid.txt"]);
+        let path = tree.0.join(std::ffi::OsStr::from_bytes(b"caf\xe9.txt"));
+        std::fs::write(&path, "foo\n").unwrap();
+        std::fs::write(tree.0.join("valid.txt"), "foo\n").unwrap();
+
+        let mut searcher = make_grep_searcher("foo", false).unwrap();
+        let (mut tx, rx) = mpsc::sync_channel(10);
+        let root = Arc::new(tree.0.clone());
+        for p in [path.clone(), tree.0.join("valid.txt")] {
+            grep_file(&mut searcher, unibyte, root.clone(), p, find::Kind::File, &mut tx).unwrap();
+        }
+        drop(tx);
+        (path, rx.iter().collect())
+    }
+
+    #[test]
+    fn grep_reports_invalid_name_and_goes_on() {
+        let (path, results) = grep_latin1_name(false);
+        assert_eq!(results.len(), 2);
+        match &results[0] {
+            Err(e) => {
+                assert_eq!(e.kind, io::ErrorKind::InvalidData);
+                assert_eq!(e.path, path.to_string_lossy());
+            }
+            Ok(_) => panic!("match reported for invalid file name"),
+        }
+        match &results[1] {
+            Ok(m) => assert_eq!(m.rel_path.as_str(), "valid.txt"),
+            Err(e) => panic!("unexpected error: {}", e.message),
+        }
+    }
+
+    #[test]
+    fn grep_unibyte_name_round_trips() {
+        let (path, results) = grep_latin1_name(true);
+        assert_eq!(results.len(), 2);
+        match &results[0] {
+            Ok(m) => {
+                assert_eq!(m.abs_path.file_name(), &FileName::Bytes(path.as_os_str().as_bytes().to_vec()));
+                assert_eq!(m.rel_path.file_name(), &FileName::Bytes(b"caf\xe9.txt".to_vec()));
+            }
+            Err(e) => panic!("unexpected error: {}", e.message),
+        }
+        assert!(results[1].is_ok());
+    }
+
+    #[test]
+    fn rank_found_keeps_best_matches() {
+        let tree = TempTree::new("rank-found", &["src/main.rs", "src/lib.rs", "docs/readme.md", "target/main.rs"]);
+        let it = |xs: &[&str]| xs.iter().map(|x| Ok::<_, anyhow::Error>(x.to_string())).collect::<Vec<_>>().into_iter();
+        let ignores = find::Ignores::new(it(&["*"]), it(&[]), it(&["target"]), it(&[]), it(&[])).unwrap();
+        let opts = find::Opts::default();
+        let roots = [tree.0.to_string_lossy().into_owned()];
+        let rank = |needle, limit| {
+            let (res, matches, errors) =
+                rank_found(&roots, &ignores, &opts, false, &RelativeTo::Root, needle, &['/'], limit, || false);
+            assert!(res.is_ok());
+            assert!(errors.is_empty());
+            matches.into_iter().map(|m| m.item.1.to_string_lossy().into_owned()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(rank("main", None), ["src/main.rs"]);
+        let all = rank("rs", None);
+        let mut sorted = all.clone();
+        sorted.sort();
+        assert_eq!(sorted, ["src/lib.rs", "src/main.rs"]);
+        assert_eq!(rank("rs", Some(1)), all[..1]);
+        assert!(rank("xyz", None).is_empty());
+    }
+
+    #[test]
+    fn relative_to_base_requires_absolute_roots() {
+        let base = PathBuf::from("/home/user");
+        assert!(RelativeTo::base(base.clone(), &["src".to_string()]).is_err());
+        assert!(RelativeTo::base(base.clone(), &["/tmp".to_string(), "./src".to_string()]).is_err());
+        let r = RelativeTo::base(base, &["/home/user/src".to_string()]).unwrap();
+        assert_eq!(r.apply(Path::new("/home/user/src"), PathBuf::from("/home/user/src/a.rs")), Path::new("src/a.rs"));
+        assert_eq!(r.apply(Path::new("/home/user/src"), PathBuf::from("/opt/b.rs")), Path::new("../../opt/b.rs"));
+    }
+}
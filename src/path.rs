@@ -12,25 +12,99 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::borrow::Cow;
+use std::fmt;
 use std::path::PathBuf;
 use std::result::Result;
 
 use emacs;
-use emacs::{Env, Value};
+use emacs::{Env, IntoLisp, Value};
+
+emacs::use_symbols!(unibyte_string);
+
+/// File name in the form it is handed over to Emacs.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FileName {
+    Text(String),
+    /// Name that isn’t valid UTF-8, passed to Emacs as a unibyte string. File functions
+    /// use such strings as is, so the name refers to the same file on the way back.
+    Bytes(Vec<u8>),
+}
+
+/// Path that can’t be handed over to Emacs, given back to the caller.
+#[derive(Debug)]
+pub struct InvalidName(pub PathBuf);
+
+impl fmt::Display for InvalidName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid file name: {:?}", self.0)
+    }
+}
+
+impl FileName {
+    /// Name of `path` if it’s valid UTF-8. Otherwise, if `unibyte` is true, its raw bytes,
+    /// which are only available on Unix.
+    pub fn new(path: PathBuf, unibyte: bool) -> Result<Self, InvalidName> {
+        match path.into_os_string().into_string() {
+            Ok(s) => Ok(FileName::Text(s)),
+            Err(s) if unibyte => raw_bytes(s)
+                .map(FileName::Bytes)
+                .map_err(|s| InvalidName(PathBuf::from(s))),
+            Err(s) => Err(InvalidName(PathBuf::from(s))),
+        }
+    }
+
+    /// Name for places that only take text, with invalid bytes replaced.
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        match self {
+            FileName::Text(s) => Cow::Borrowed(s),
+            FileName::Bytes(bytes) => String::from_utf8_lossy(bytes),
+        }
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn raw_bytes(s: std::ffi::OsString) -> Result<Vec<u8>, std::ffi::OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(s.into_vec())
+}
+
+#[cfg(target_family = "windows")]
+fn raw_bytes(s: std::ffi::OsString) -> Result<Vec<u8>, std::ffi::OsString> {
+    Err(s)
+}
+
+impl<'a> IntoLisp<'a> for &FileName {
+    fn into_lisp(self, env: &'a Env) -> emacs::Result<Value<'a>> {
+        match self {
+            FileName::Text(s) => s.as_str().into_lisp(env),
+            FileName::Bytes(bytes) => {
+                let args = bytes.iter().map(|&b| i64::from(b).into_lisp(env)).collect::<emacs::Result<Vec<_>>>()?;
+                env.call(unibyte_string, &args[..])
+            }
+        }
+    }
+}
+
+impl<'a> IntoLisp<'a> for FileName {
+    fn into_lisp(self, env: &'a Env) -> emacs::Result<Value<'a>> {
+        (&self).into_lisp(env)
+    }
+}
 
 pub struct EmacsPath {
-    path: String,
+    path: FileName,
 }
 
 impl EmacsPath {
+    /// See `FileName::new` for the meaning of `unibyte`.
     // mut will be required on Windows
     #[allow(unused_mut)]
-    pub fn new(path: PathBuf) -> Result<Self, String> {
-        match path.into_os_string().into_string() {
-            Err(err) => Err(format!("Path has invalid utf8 encoding: {:?}", err)),
-            Ok(mut s) => {
+    pub fn new(path: PathBuf, unibyte: bool) -> Result<Self, InvalidName> {
+        match FileName::new(path, unibyte)? {
+            FileName::Text(mut s) => {
                 #[cfg(target_family = "windows")]
-                let path = unsafe {
+                let s = unsafe {
                     for b in s.as_bytes_mut() {
                         match b {
                             b'\\' => *b = b'/',
@@ -39,17 +113,23 @@ impl EmacsPath {
                     }
                     s
                 };
-                #[cfg(target_family = "unix")]
-                let path = s;
-                Ok(EmacsPath { path })
+                Ok(EmacsPath { path: FileName::Text(s) })
             }
+            path => Ok(EmacsPath { path }),
         }
     }
+
+    pub fn as_str(&self) -> Cow<'_, str> {
+        self.path.to_str_lossy()
+    }
+
+    pub fn file_name(&self) -> &FileName {
+        &self.path
+    }
 }
 
 impl<'a> emacs::IntoLisp<'a> for &EmacsPath {
     fn into_lisp(self, env: &'a Env) -> emacs::Result<Value<'a>> {
-        self.path.clone().into_lisp(env)
+        (&self.path).into_lisp(env)
     }
 }
-
@@ -0,0 +1,51 @@
+// Copyright 2021 Sergey Vinokurov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{self, Write};
+
+/// Writes results as lines of tab-separated fields, the first of which is a tag telling
+/// what kind of record the line is. Backslashes, tabs and newlines within fields are
+/// written as `\\`, `\t` and `\n` so that every record occupies exactly one line.
+pub struct RecordWriter<W: Write> {
+    out: W,
+    buf: String,
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(out: W) -> Self {
+        RecordWriter { out, buf: String::new() }
+    }
+
+    pub fn write(&mut self, tag: &str, fields: &[&str]) -> io::Result<()> {
+        self.buf.clear();
+        self.buf.push_str(tag);
+        for field in fields {
+            self.buf.push('\t');
+            for c in field.chars() {
+                match c {
+                    '\\' => self.buf.push_str("\\\\"),
+                    '\t' => self.buf.push_str("\\t"),
+                    '\n' => self.buf.push_str("\\n"),
+                    _ => self.buf.push(c),
+                }
+            }
+        }
+        self.buf.push('\n');
+        self.out.write_all(self.buf.as_bytes())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
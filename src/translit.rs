@@ -0,0 +1,110 @@
+// Copyright 2021 Sergey Vinokurov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use deunicode::deunicode_char;
+
+use crate::fuzzy_match::StrIdx;
+
+/// Which scripts get romanized before matching.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Transliteration {
+    /// Match Han characters by their pinyin.
+    pub han: bool,
+    /// Match hiragana and katakana by romaji.
+    pub kana: bool,
+}
+
+impl Transliteration {
+    pub fn none() -> Self {
+        Transliteration { han: false, kana: false }
+    }
+
+    pub fn all() -> Self {
+        Transliteration { han: true, kana: true }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.han || self.kana
+    }
+
+    fn applies_to(&self, c: char) -> bool {
+        (self.han && is_han(c)) || (self.kana && is_kana(c))
+    }
+}
+
+fn is_han(c: char) -> bool {
+    matches!(
+        c,
+        '\u{3400}'..='\u{4DBF}'
+            | '\u{4E00}'..='\u{9FFF}'
+            | '\u{F900}'..='\u{FAFF}'
+            | '\u{20000}'..='\u{2EBEF}'
+    )
+}
+
+fn is_kana(c: char) -> bool {
+    matches!(c, '\u{3040}'..='\u{30FF}' | '\u{FF66}'..='\u{FF9F}')
+}
+
+/// Romanized string along with index of the original character for every romanized one.
+#[derive(Default)]
+pub struct Transliterated {
+    pub text: String,
+    pub orig_idx: Vec<StrIdx>,
+}
+
+impl Transliterated {
+    pub fn new() -> Self {
+        Transliterated {
+            text: String::new(),
+            orig_idx: Vec::new(),
+        }
+    }
+}
+
+/// Replace every character covered by `opts` with its romanization. Each syllable gets
+/// capitalized so that heatmap treats it as a word start and initials like "bj" match "北京".
+/// Returns false if nothing was replaced, in which case `out` should not be used.
+pub fn transliterate(s: &str, opts: Transliteration, out: &mut Transliterated) -> bool {
+    out.text.clear();
+    out.orig_idx.clear();
+
+    let mut changed = false;
+
+    for (i, c) in s.chars().enumerate() {
+        let romanized = if opts.applies_to(c) {
+            deunicode_char(c)
+                .map(|r| r.trim())
+                .filter(|r| !r.is_empty() && r.chars().all(|x| x.is_ascii_alphabetic()))
+        } else {
+            None
+        };
+
+        match romanized {
+            None => {
+                out.text.push(c);
+                out.orig_idx.push(i as StrIdx);
+            }
+            Some(r) => {
+                changed = true;
+                for (j, x) in r.chars().enumerate() {
+                    out.text.push(if j == 0 { x.to_ascii_uppercase() } else { x.to_ascii_lowercase() });
+                    out.orig_idx.push(i as StrIdx);
+                }
+            }
+        }
+    }
+
+    changed
+}